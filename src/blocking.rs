@@ -0,0 +1,67 @@
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{Client, ClientOptions, Operation, PartialResponse, Result};
+
+/// A [`Client`] for code that isn't async. Each operation blocks the current
+/// thread until it completes, so it must not be used from within an async
+/// runtime
+pub struct BlockingClient {
+    client: Client,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl BlockingClient {
+    pub fn new(options: ClientOptions) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| anyhow::anyhow!("failed to create runtime: {}", e))?;
+        Ok(Self {
+            client: Client::try_new(options)?,
+            runtime,
+        })
+    }
+
+    pub fn query<P, I, R>(&self, subpath: P, input: I) -> Result<R>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        self.runtime.block_on(self.client.query(subpath, input))
+    }
+
+    pub fn query_with_response<P, I, R>(&self, subpath: P, input: I) -> Result<(R, HeaderMap)>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        self.runtime
+            .block_on(self.client.query_with_response(subpath, input))
+    }
+
+    pub fn query_partial<P, I, R>(&self, subpath: P, input: I) -> Result<PartialResponse<R>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        self.runtime
+            .block_on(self.client.query_partial(subpath, input))
+    }
+
+    pub fn mutate<P, I, R>(&self, subpath: P, input: I) -> Result<R>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        self.runtime.block_on(self.client.mutate(subpath, input))
+    }
+
+    pub fn execute<O: Operation>(&self, input: O::Input) -> Result<O::Output> {
+        self.runtime.block_on(self.client.execute::<O>(input))
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::{HashMap, VecDeque};
+
+use reqwest::header::{HeaderValue, ETAG};
+
+use crate::client::{QueryKey, RawResponse};
+
+/// Responses of queries which were sent with an `ETag`, so they can be
+/// revalidated instead of downloaded again. Once full, the oldest entry is
+/// evicted
+pub(crate) struct EtagCache {
+    max_entries: usize,
+    entries: HashMap<QueryKey, CachedResponse>,
+    /// Keys in the order they were inserted, oldest first
+    order: VecDeque<QueryKey>,
+}
+
+#[derive(Clone)]
+pub(crate) struct CachedResponse {
+    pub(crate) etag: HeaderValue,
+    pub(crate) response: RawResponse,
+}
+
+impl EtagCache {
+    pub(crate) fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &QueryKey) -> Option<CachedResponse> {
+        self.entries.get(key).cloned()
+    }
+
+    /// Stores the response if it has an `ETag`. Otherwise any previously
+    /// cached response is dropped, since it can't be revalidated anymore
+    pub(crate) fn insert(&mut self, key: QueryKey, response: &RawResponse) {
+        let Some(etag) = response.headers.get(ETAG) else {
+            if self.entries.remove(&key).is_some() {
+                self.order.retain(|k| k != &key);
+            }
+            return;
+        };
+        if self.max_entries == 0 {
+            return;
+        }
+        let entry = CachedResponse {
+            etag: etag.clone(),
+            response: response.clone(),
+        };
+        if self.entries.insert(key.clone(), entry).is_some() {
+            return;
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.max_entries {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
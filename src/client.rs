@@ -1,128 +1,1928 @@
 // Based on Kirill Valiavin's initial client implementation
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    future::Future,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
 use async_stream::stream;
+use bytes::Bytes;
 pub use futures_core::stream::Stream;
+use futures_util::future::{
+    self, AbortHandle, AbortRegistration, Abortable, BoxFuture, Either, FutureExt, Pending, Shared,
+};
 pub use futures_util::stream::StreamExt;
-use reqwest::Url;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, IF_NONE_MATCH},
+    Method, RequestBuilder, StatusCode, Url,
+};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error, field, info_span, trace, Instrument, Span};
 
+#[cfg(feature = "websocket")]
+use crate::SubscriptionManager;
 use crate::{
-    errors::{Error, GraphQLErrors, Result},
-    ResponseError,
+    cache::EtagCache,
+    errors::truncate_body,
+    errors::{Error, GraphQLError, Result},
+    frames::FrameBuffer,
+    incremental::{decode_part, multipart_boundary, MultipartBuffer},
+    metrics::OperationMetrics,
+    rate_limit::RateLimiter,
+    retry::retry_after,
+    ClientOptions, HeaderProvider, IncrementalPayload, Interceptor, MetricHook, Operation,
+    OperationKind, OperationType, QueryParamNames, ReconnectPolicy, RequestIdGenerator,
+    RequestOptions, RequestSigner, ResponseError, RetryPolicy, StreamItem, SubscriptionOperation,
+    SubscriptionTransport, UnauthorizedHook,
 };
+#[cfg(feature = "json-patch")]
+use crate::{patch::PatchedResponse, LiveQueryUpdate};
 
-#[derive(Default, Clone)]
-pub struct ClientOptions {
-    pub url: Option<Url>,
-    pub application_hash: Option<String>,
-}
-
+/// Client for the operations of a WunderGraph application. Cloning it is
+/// cheap, since clones share the same connection pool
+#[derive(Clone)]
 pub struct Client {
     client: reqwest::Client,
     url: Url,
+    health_url: Url,
+    app_url: Url,
     application_hash: String,
+    timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    /// Shared between clones, so a token refreshed by `on_unauthorized` is
+    /// used by all of them
+    bearer_token: Arc<RwLock<Option<String>>>,
+    on_unauthorized: Option<UnauthorizedHook>,
+    headers: HeaderMap,
+    header_provider: Option<HeaderProvider>,
+    generate_request_id: Option<RequestIdGenerator>,
+    signer: Option<RequestSigner>,
+    signature_header: String,
+    operations: Option<&'static [&'static str]>,
+    accept: String,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    on_metric: Option<MetricHook>,
+    reconnect: Option<ReconnectPolicy>,
+    in_flight: Option<InFlightQueries>,
+    etag_cache: Option<Arc<Mutex<EtagCache>>>,
+    max_response_bytes: Option<usize>,
+    log_response_bodies: bool,
+    idle_timeout: Option<Duration>,
+    skip_undecodable_frames: bool,
+    post_variables_threshold: Option<usize>,
+    force_post_queries: bool,
+    pretty_variables: bool,
+    retry: RetryPolicy,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Limits the number of operations in flight, shared between clones
+    concurrency: Option<Arc<Semaphore>>,
+    param_names: QueryParamNames,
+    subscription_transport: SubscriptionTransport,
+    #[cfg(feature = "websocket")]
+    websocket_url: Url,
+    #[cfg(feature = "cancellation")]
+    shutdown: Option<tokio_util::sync::CancellationToken>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("url", &self.url.as_str())
+            .field("application_hash", &redact(&self.application_hash))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Sets the field at the dot separated path to `null`, creating it if its
+/// parent exists but doesn't have it yet
+#[cfg(feature = "multipart")]
+fn set_null(value: &mut serde_json::Value, path: &str) {
+    let mut value = value;
+    let mut segments = path.split('.').peekable();
+    while let Some(segment) = segments.next() {
+        let last = segments.peek().is_none();
+        let next = match value {
+            serde_json::Value::Object(fields) if last => {
+                fields.insert(segment.to_string(), serde_json::Value::Null);
+                return;
+            }
+            serde_json::Value::Object(fields) => fields.get_mut(segment),
+            serde_json::Value::Array(items) => segment
+                .parse()
+                .ok()
+                .and_then(|index: usize| items.get_mut(index)),
+            _ => None,
+        };
+        match next {
+            Some(next) => value = next,
+            None => return,
+        }
+    }
+    *value = serde_json::Value::Null;
+}
+
+/// Keeps only the start of a value which might be a secret, so it can be
+/// recognized in logs without being leaked
+fn redact(value: &str) -> String {
+    const VISIBLE_CHARS: usize = 4;
+    match value.char_indices().nth(VISIBLE_CHARS) {
+        Some((end, _)) => format!("{}...", &value[..end]),
+        None => value.to_string(),
+    }
+}
+
+/// Identifies an operation together with its variables and the application
+/// it belongs to, e.g. to use as the key of a map of responses
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OperationKey {
+    pub subpath: String,
+    /// Hash of the variables, serialized to JSON by serde_json, or 0 without
+    /// variables. The JSON is hashed with [`DefaultHasher::new`], so the hash
+    /// is the same for equal variables in every run of a program, but might
+    /// change with the Rust version it's built with. Object fields are hashed
+    /// in the order they're serialized in, which is the order of the fields
+    /// of a struct
+    pub variables_hash: u64,
+    pub app_hash: String,
+}
+
+impl OperationKey {
+    pub fn new(subpath: &str, variables: Option<&str>, app_hash: &str) -> Self {
+        let variables_hash = match variables {
+            Some(variables) => {
+                let mut hasher = DefaultHasher::new();
+                variables.hash(&mut hasher);
+                hasher.finish()
+            }
+            None => 0,
+        };
+        Self {
+            subpath: subpath.to_string(),
+            variables_hash,
+            app_hash: app_hash.to_string(),
+        }
+    }
+}
+
+/// A query as it's sent to the server, queries with equal keys are identical.
+/// The variables are kept in full, since responses mustn't be mixed up if
+/// their hashes collide
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct QueryKey {
+    operation: OperationKey,
+    /// The serialized variables
+    variables: Option<String>,
+    /// Additional query parameters
+    params: Vec<(String, String)>,
+    /// The method to send the query with, if not chosen by the client
+    method: Option<Method>,
+}
+
+/// Queries currently being sent
+type InFlightQueries = Arc<Mutex<HashMap<QueryKey, SharedResponse>>>;
+
+type SharedResponse = Shared<BoxFuture<'static, std::result::Result<RawResponse, Arc<Error>>>>;
+
+/// A query waiting for a shared response. If the last one waiting is dropped
+/// before the response arrives, the request is dropped from the in-flight
+/// queries, so later queries send a new one instead of resuming it
+struct InFlightWaiter {
+    key: QueryKey,
+    in_flight: InFlightQueries,
+    /// `None` once the response has arrived
+    response: Option<SharedResponse>,
+}
+
+impl Drop for InFlightWaiter {
+    fn drop(&mut self) {
+        let Some(response) = self.response.take() else {
+            return;
+        };
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // The map holds the only other handle
+        let abandoned = in_flight
+            .get(&self.key)
+            .is_some_and(|entry| entry.ptr_eq(&response) && response.strong_count() == Some(2));
+        let entry = abandoned.then(|| in_flight.remove(&self.key));
+        drop(in_flight);
+        // Dropping the last handles outside the lock cancels the request
+        drop(entry);
+    }
 }
 
-#[derive(Deserialize, Serialize)]
-struct ResponseData<T> {
-    pub data: T,
+/// Creates the span covering an operation, its status code and whether it
+/// failed with a GraphQL error are recorded once they're known
+macro_rules! operation_span {
+    ($name:literal, $operation_type:literal, $subpath:expr) => {
+        info_span!(
+            $name,
+            subpath = %$subpath,
+            operation_type = $operation_type,
+            status_code = field::Empty,
+            graphql_error = field::Empty,
+            request_id = field::Empty,
+        )
+    };
 }
 
-#[derive(Deserialize, Serialize)]
-#[serde(untagged)]
-enum Response<T> {
-    Data(ResponseData<T>),
-    Error(GraphQLErrors),
+/// A response whose body has been read, but not decoded yet
+#[derive(Clone)]
+pub(crate) struct RawResponse {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Bytes,
+}
+
+impl RawResponse {
+    fn decode<T>(&self, subpath: &str) -> Result<T>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        self.check_content_type(subpath)?;
+        let result = decode_bytes(subpath, self.status, &self.body);
+        with_request_id(result, request_id(&self.headers).as_deref())
+    }
+
+    /// Fails if the request failed and the body isn't JSON, e.g. because a
+    /// proxy returned an HTML error page. There's no point trying to decode
+    /// it in that case, and the body is more useful than a decoding error
+    fn check_content_type(&self, subpath: &str) -> Result<()> {
+        if self.status.is_success() {
+            return Ok(());
+        }
+        let Some(content_type) = self.headers.get(CONTENT_TYPE) else {
+            return Ok(());
+        };
+        let content_type = String::from_utf8_lossy(content_type.as_bytes());
+        if is_json(&content_type) {
+            return Ok(());
+        }
+        error!(
+            "request to {} failed with status {} and content type {}",
+            subpath,
+            self.status.as_u16(),
+            content_type
+        );
+        Err(Error::NonJsonErrorResponse {
+            status: self.status.as_u16(),
+            content_type: content_type.into_owned(),
+            body: truncate_body(&self.body),
+        })
+    }
+}
+
+const EVENT_STREAM: &str = "text/event-stream";
+
+/// Header identifying a request, set by the server or the client
+const REQUEST_ID: &str = "x-request-id";
+
+/// Returns the ID the server assigned to the request, if any
+fn request_id(headers: &HeaderMap) -> Option<String> {
+    let request_id = headers.get(REQUEST_ID)?.to_str().ok()?;
+    Some(request_id.to_string())
+}
+
+/// Attaches the ID of the request to GraphQL errors in its response
+fn with_request_id<T>(result: Result<T>, request_id: Option<&str>) -> Result<T> {
+    match result {
+        Err(Error::ResponseError(mut error)) => {
+            error.request_id = request_id.map(str::to_string);
+            Err(error.into())
+        }
+        result => result,
+    }
+}
+
+/// Accept header of queries using incremental delivery
+const INCREMENTAL_DELIVERY: &str = "multipart/mixed; deferSpec=20220824, application/json";
+
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE) else {
+        return false;
+    };
+    let content_type = String::from_utf8_lossy(content_type.as_bytes());
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case(EVENT_STREAM)
+}
+
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case("application/json") || essence.ends_with("+json")
+}
+
+/// The body of a response. Which fields are set tells data and errors apart
+#[derive(Deserialize)]
+struct ResponseBody<T> {
+    /// `None` if the response has no data or it's `null`, even if `T` is an
+    /// `Option` itself
+    data: Option<T>,
+    code: Option<String>,
+    errors: Option<Vec<GraphQLError>>,
+}
+
+/// A query sent as part of [`Client::batch`]
+#[derive(Debug, Clone)]
+pub struct BatchRequest {
+    pub subpath: String,
+    pub input: serde_json::Value,
+}
+
+/// A response which might contain both data and errors, e.g. when resolving
+/// some of the fields failed
+#[derive(Debug)]
+pub struct PartialResponse<T> {
+    pub data: Option<T>,
+    pub errors: Vec<GraphQLError>,
 }
 
 impl Client {
+    /// Creates a client from the given options.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the options are invalid, see [`Client::try_new`] for a
+    /// version which returns an error instead
     pub fn new(options: ClientOptions) -> Self {
-        let base = options
-            .url
-            .unwrap_or_else(|| Url::parse("http://localhost:9991/").unwrap());
+        Self::try_new(options).expect("invalid client options")
+    }
+
+    /// Creates a client from the given options. Fails if the URL can't have
+    /// paths joined to it, e.g. a `data:` URL, if no URL is set although
+    /// [`ClientOptions::require_url`] is, or if the HTTP client can't be built
+    pub fn try_new(options: ClientOptions) -> Result<Self> {
+        let client = match &options.http_client {
+            Some(client) => client.clone(),
+            None => build_http_client(&options)
+                .map_err(|e| anyhow::anyhow!("failed to build HTTP client: {}", e))?,
+        };
+        let base = match options.url {
+            Some(url) => url,
+            None if options.require_url => {
+                return Err(anyhow::anyhow!("no URL set for the client").into())
+            }
+            None => Url::parse("http://localhost:9991/")?,
+        };
         let application_hash = options.application_hash.unwrap_or_default();
-        Self {
-            client: reqwest::Client::new(),
-            url: base.join("/operations/").unwrap(),
+        let mut operations_path = options
+            .operations_path
+            .unwrap_or_else(|| "/operations/".to_string());
+        // Without a trailing slash, joining the subpath would replace the last
+        // segment instead of appending to it
+        if !operations_path.ends_with('/') {
+            operations_path.push('/');
+        }
+        #[cfg(feature = "websocket")]
+        let websocket_url = match options.websocket_url {
+            Some(url) => url,
+            None => {
+                let mut url = base.join("/graphql")?;
+                let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+                url.set_scheme(scheme)
+                    .map_err(|_| anyhow::anyhow!("can't derive a WebSocket URL from {}", base))?;
+                url
+            }
+        };
+        Ok(Self {
+            client,
+            url: base.join(&operations_path)?,
+            health_url: base.join("/health")?,
+            app_url: base.join("/app")?,
             application_hash,
+            timeout: options.timeout,
+            handshake_timeout: options.handshake_timeout,
+            bearer_token: Arc::new(RwLock::new(options.bearer_token)),
+            on_unauthorized: options.on_unauthorized,
+            headers: options.headers,
+            header_provider: options.header_provider,
+            generate_request_id: options.generate_request_id,
+            signer: options.signer,
+            signature_header: options
+                .signature_header
+                .unwrap_or_else(|| "X-Signature".to_string()),
+            operations: options.operations,
+            accept: options
+                .accept
+                .unwrap_or_else(|| "application/json".to_string()),
+            interceptors: options.interceptors,
+            on_metric: options.on_metric,
+            reconnect: options.reconnect,
+            in_flight: options.dedupe.then(Default::default),
+            etag_cache: options
+                .etag_cache_size
+                .map(|size| Arc::new(Mutex::new(EtagCache::new(size)))),
+            max_response_bytes: options.max_response_bytes,
+            log_response_bodies: options.log_response_bodies,
+            idle_timeout: options.idle_timeout,
+            skip_undecodable_frames: options.skip_undecodable_frames,
+            post_variables_threshold: options.post_variables_threshold,
+            force_post_queries: options.force_post_queries,
+            pretty_variables: options.pretty_variables,
+            retry: options.retry,
+            rate_limiter: options
+                .rate_limit
+                .map(|limit| Arc::new(RateLimiter::new(limit))),
+            concurrency: options
+                .max_concurrent
+                .map(|max| Arc::new(Semaphore::new(max))),
+            param_names: options.param_names,
+            subscription_transport: options.subscription_transport,
+            #[cfg(feature = "websocket")]
+            websocket_url,
+            #[cfg(feature = "cancellation")]
+            shutdown: options.shutdown,
+        })
+    }
+
+    /// Returns the HTTP client used to send requests
+    pub fn http_client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    pub fn application_hash(&self) -> &str {
+        &self.application_hash
+    }
+
+    /// Sets the application hash sent by subsequent requests. Requests already
+    /// in flight keep using the previous one. This takes `&mut self`, so to
+    /// change the hash of a client shared between tasks, wrap it in a lock or
+    /// give each task its own clone
+    pub fn set_application_hash(&mut self, hash: String) {
+        self.application_hash = hash;
+    }
+
+    /// Sets or clears the bearer token used by subsequent requests. The token
+    /// is shared with the client's clones, so they use it as well
+    pub fn set_bearer_token(&mut self, token: Option<String>) {
+        *self.bearer_token.write().unwrap() = token;
+    }
+
+    /// Returns the key identifying the operation with the given input, as sent
+    /// by this client
+    pub fn operation_key<I>(&self, subpath: &str, input: &I) -> Result<OperationKey>
+    where
+        I: Serialize,
+    {
+        let variables = self.serialize_variables(input)?;
+        Ok(OperationKey::new(
+            subpath,
+            Some(&variables),
+            &self.application_hash,
+        ))
+    }
+
+    /// Returns the URL [`Client::query`] would send the query with the given
+    /// input to, without sending it, e.g. to log it or to try it with curl.
+    /// Queries sent as POST requests, see
+    /// [`ClientOptions::post_variables_threshold`], have the variables in the
+    /// body instead
+    pub fn operation_url<P, I>(&self, subpath: P, input: I) -> Result<Url>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+    {
+        let subpath = subpath.as_ref();
+        self.check_operation(subpath)?;
+        let key = self.query_key(subpath, Some(input), &RequestOptions::default())?;
+        let mut url = self.url.join(subpath)?;
+        {
+            let mut pairs = url.query_pairs_mut();
+            pairs.append_pair(&self.param_names.app_hash, &self.application_hash);
+            if let Some(variables) = &key.variables {
+                if self.query_method(&key) == Method::GET {
+                    pairs.append_pair(&self.param_names.variables, variables);
+                }
+            }
+            for (name, value) in &key.params {
+                pairs.append_pair(name, value);
+            }
+        }
+        Ok(url)
+    }
+
+    /// Serializes the variables of a query, subscription or live query
+    fn serialize_variables<I>(&self, input: &I) -> serde_json::Result<String>
+    where
+        I: Serialize,
+    {
+        if self.pretty_variables {
+            serde_json::to_string_pretty(input)
+        } else {
+            serde_json::to_string(input)
+        }
+    }
+
+    /// Drops all responses kept for revalidating queries via their `ETag`
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.etag_cache {
+            cache.lock().unwrap().clear();
+        }
+    }
+
+    /// Checks whether the server is reachable and healthy, using its `/health`
+    /// endpoint. Fails with [`Error::InvalidHTTPStatusCodeError`] unless the
+    /// server responds with a success status. The request isn't retried, so
+    /// an unreachable server is reported right away
+    pub async fn health_check(&self) -> Result<()> {
+        let req = self.client.get(self.health_url.clone());
+        let req = match self.timeout {
+            Some(timeout) => req.timeout(timeout),
+            None => req,
+        };
+        let resp = self
+            .until_shutdown(async { req.send().await.map_err(send_error) })
+            .await?;
+        if !resp.status().is_success() {
+            error!("health check failed with status {}", resp.status().as_u16());
+            return Err(Error::InvalidHTTPStatusCodeError(resp.status().as_u16()));
         }
+        Ok(())
+    }
+
+    /// Fetches the current application hash from the server and uses it for
+    /// subsequent requests. The server is expected to respond to `GET /app`
+    /// with a JSON object like `{"applicationHash": "..."}`. If it doesn't,
+    /// e.g. because it doesn't serve that endpoint, the hash is left as it is.
+    /// Only failing to reach the server is an error
+    pub async fn refresh_application_hash(&mut self) -> Result<()> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct AppInfo {
+            application_hash: String,
+        }
+
+        let req = self.client.get(self.app_url.clone());
+        let req = match self.timeout {
+            Some(timeout) => req.timeout(timeout),
+            None => req,
+        };
+        let resp = self
+            .until_shutdown(async { req.send().await.map_err(send_error) })
+            .await?;
+        if !resp.status().is_success() {
+            debug!(
+                "not refreshing the application hash, server responded with status {}",
+                resp.status().as_u16()
+            );
+            return Ok(());
+        }
+        let resp = read_response(resp, self.max_response_bytes).await?;
+        match serde_json::from_slice::<AppInfo>(&resp.body) {
+            Ok(info) => self.application_hash = info.application_hash,
+            Err(e) => debug!("not refreshing the application hash: {}", e),
+        }
+        Ok(())
+    }
+
+    /// Returns a request builder for the given operation, with the settings
+    /// shared by all operation types already applied
+    fn request(&self, method: Method, subpath: &str) -> Result<RequestBuilder> {
+        self.check_operation(subpath)?;
+        self.unchecked_request(method, subpath)
+    }
+
+    /// Fails if the operation isn't one of [`ClientOptions::operations`]
+    pub(crate) fn check_operation(&self, subpath: &str) -> Result<()> {
+        match self.operations {
+            Some(operations) if !operations.contains(&subpath) => {
+                Err(Error::UnknownOperation(subpath.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Like [`Client::request`], without checking the operation
+    fn unchecked_request(&self, method: Method, subpath: &str) -> Result<RequestBuilder> {
+        let url = self.url.join(subpath)?;
+
+        let req = self
+            .client
+            .request(method, url)
+            .query(&[(&self.param_names.app_hash, &self.application_hash)])
+            .header("Accept", &self.accept)
+            .header("Content-Type", "application/json");
+
+        let req = match &*self.bearer_token.read().unwrap() {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        };
+
+        let req = req.headers(self.headers.clone());
+
+        let req = match &self.header_provider {
+            Some(header_provider) => req.headers(header_provider()),
+            None => req,
+        };
+
+        let req = match &self.generate_request_id {
+            Some(generate_request_id) => {
+                let request_id = generate_request_id();
+                Span::current().record("request_id", request_id.as_str());
+                req.header(REQUEST_ID, request_id)
+            }
+            None => req,
+        };
+
+        Ok(req)
+    }
+
+    /// Query parameters sent with an operation, besides the variables and the
+    /// ones sent with every request
+    fn request_params(&self, options: &RequestOptions) -> Vec<(String, String)> {
+        let mut params = options.query_params.clone();
+        if let Some(operation_hash) = &options.operation_hash {
+            params.push((
+                self.param_names.operation_hash.clone(),
+                operation_hash.clone(),
+            ));
+        }
+        params
+    }
+
+    fn query_key<I>(
+        &self,
+        subpath: &str,
+        input: Option<I>,
+        options: &RequestOptions,
+    ) -> Result<QueryKey>
+    where
+        I: Serialize,
+    {
+        let variables = input
+            .map(|input| self.serialize_variables(&input))
+            .transpose()?;
+        Ok(QueryKey {
+            operation: OperationKey::new(subpath, variables.as_deref(), &self.application_hash),
+            variables,
+            params: self.request_params(options),
+            method: options.method.clone(),
+        })
+    }
+
+    /// Sends a query and reads its response. If deduplication is enabled and
+    /// an identical query is already in flight, its response is shared instead
+    async fn fetch_query<I>(
+        &self,
+        subpath: &str,
+        input: Option<I>,
+        options: &RequestOptions,
+    ) -> Result<RawResponse>
+    where
+        I: Serialize,
+    {
+        let key = self.query_key(subpath, input, options)?;
+
+        let Some(in_flight) = &self.in_flight else {
+            return self.load_query(&key).await;
+        };
+
+        // Set if this caller sends the request, to get its error as it is
+        let mut original_error = None;
+        let response = in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let client = self.clone();
+                let in_flight = in_flight.clone();
+                let key = key.clone();
+                let error_slot = Arc::new(Mutex::new(None));
+                original_error = Some(error_slot.clone());
+                async move {
+                    let result = client.load_query(&key).await;
+                    in_flight.lock().unwrap().remove(&key);
+                    result.map_err(|error| {
+                        let copy = Arc::new(shared_error(&error));
+                        *error_slot.lock().unwrap() = Some(error);
+                        copy
+                    })
+                }
+                .boxed()
+                .shared()
+            })
+            .clone();
+
+        let mut waiter = InFlightWaiter {
+            key,
+            in_flight: in_flight.clone(),
+            response: Some(response),
+        };
+        let result = waiter.response.as_mut().unwrap().await;
+        waiter.response = None;
+        result.map_err(|error| {
+            let original = original_error.and_then(|slot| slot.lock().unwrap().take());
+            original.unwrap_or_else(|| shared_error(&error))
+        })
+    }
+
+    /// Reads the whole body of an operation's response
+    async fn read_response(&self, subpath: &str, resp: reqwest::Response) -> Result<RawResponse> {
+        let resp = read_response(resp, self.max_response_bytes).await?;
+        if self.log_response_bodies {
+            log_body(subpath, resp.status, &resp.body);
+        }
+        Ok(resp)
+    }
+
+    async fn decode_response<T>(
+        &self,
+        subpath: &str,
+        resp: reqwest::Response,
+    ) -> Result<(T, HeaderMap)>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let resp = self.read_response(subpath, resp).await?;
+        let data = resp.decode(subpath)?;
+        Ok((data, resp.headers))
+    }
+
+    /// Sends a query and reads its response. If the ETag cache holds a
+    /// response for the query, it's revalidated and reused if still current
+    async fn load_query(&self, key: &QueryKey) -> Result<RawResponse> {
+        let _permit = self.concurrency_permit().await;
+        let Some(cache) = &self.etag_cache else {
+            let resp = self.send_query(key, None).await?;
+            return self.read_response(&key.operation.subpath, resp).await;
+        };
+
+        let cached = cache.lock().unwrap().get(key);
+        let etag = cached.as_ref().map(|cached| cached.etag.clone());
+        let resp = self.send_query(key, etag).await?;
+        if let Some(cached) = cached.filter(|_| resp.status() == StatusCode::NOT_MODIFIED) {
+            debug!(
+                "query to {} not modified, using cached response",
+                key.operation.subpath
+            );
+            return Ok(cached.response);
+        }
+
+        let resp = self.read_response(&key.operation.subpath, resp).await?;
+        if resp.status.is_success() {
+            cache.lock().unwrap().insert(key.clone(), &resp);
+        }
+        Ok(resp)
+    }
+
+    /// Sends a query. Without variables the parameter is omitted entirely.
+    /// Variables too long to fit in the URL are sent in a POST body instead
+    async fn send_query(
+        &self,
+        key: &QueryKey,
+        etag: Option<HeaderValue>,
+    ) -> Result<reqwest::Response> {
+        let req = self.query_request(key)?;
+        let req = match etag {
+            Some(etag) => req.header(IF_NONE_MATCH, etag),
+            None => req,
+        };
+
+        debug!("query: {:?}", req);
+
+        self.send(req, true).await
+    }
+
+    /// Builds the request a query is sent with
+    fn query_request(&self, key: &QueryKey) -> Result<RequestBuilder> {
+        let subpath = &key.operation.subpath;
+        let method = self.query_method(key);
+        // Only GET requests have the variables in the URL
+        let req = match &key.variables {
+            Some(variables) if method != Method::GET => {
+                self.request(method, subpath)?.body(variables.clone())
+            }
+            Some(variables) => self
+                .request(method, subpath)?
+                .query(&[(&self.param_names.variables, variables)]),
+            None => self.request(method, subpath)?,
+        };
+        let req = req.query(&key.params);
+        let variables = key.variables.as_deref().unwrap_or_default();
+        Ok(self.sign(req, variables.as_bytes()))
+    }
+
+    /// Returns the method a query is sent with, queries with large variables
+    /// are sent as POST requests if configured
+    fn query_method(&self, key: &QueryKey) -> Method {
+        match &key.method {
+            Some(method) => method.clone(),
+            None if self.force_post_queries => Method::POST,
+            None if key.variables.as_ref().is_some_and(|variables| {
+                self.post_variables_threshold
+                    .is_some_and(|threshold| variables.len() > threshold)
+            }) =>
+            {
+                Method::POST
+            }
+            None => Method::GET,
+        }
+    }
+
+    /// Adds the signature of the given payload, if requests are signed
+    fn sign(&self, req: RequestBuilder, payload: &[u8]) -> RequestBuilder {
+        match &self.signer {
+            Some(signer) => req.header(&self.signature_header, signer(payload)),
+            None => req,
+        }
+    }
+
+    /// Sends a query or mutation request, retrying it according to the retry
+    /// policy if it's idempotent
+    async fn send(&self, req: RequestBuilder, idempotent: bool) -> Result<reqwest::Response> {
+        let req = match self.timeout {
+            Some(timeout) => req.timeout(timeout),
+            None => req,
+        };
+        let req = self.intercept_request(req).await;
+
+        let Some(on_unauthorized) = &self.on_unauthorized else {
+            return self.send_attempts(req, idempotent).await;
+        };
+        let unauthorized_retry = req.try_clone();
+        let resp = self.send_attempts(req, idempotent).await?;
+        let Some(retry) = unauthorized_retry.filter(|_| resp.status() == StatusCode::UNAUTHORIZED)
+        else {
+            return Ok(resp);
+        };
+
+        // The request wasn't executed, so even mutations can be sent again
+        debug!("request to {} unauthorized, refreshing token", resp.url());
+        let token = on_unauthorized().await?;
+        let mut authorization = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|e| anyhow::anyhow!("invalid bearer token: {}", e))?;
+        authorization.set_sensitive(true);
+        *self.bearer_token.write().unwrap() = Some(token);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization);
+        self.send_attempts(retry.headers(headers), idempotent).await
+    }
+
+    /// Sends the request, retrying it according to the retry policy
+    async fn send_attempts(
+        &self,
+        mut req: RequestBuilder,
+        idempotent: bool,
+    ) -> Result<reqwest::Response> {
+        let retry = idempotent || self.retry.retry_mutations;
+        let mut attempt = 1;
+        loop {
+            let next = if retry && attempt < self.retry.max_attempts {
+                req.try_clone()
+            } else {
+                None
+            };
+
+            self.wait_for_rate_limit().await;
+            let result = req.send().await;
+            if let Ok(resp) = &result {
+                Span::current().record("status_code", resp.status().as_u16());
+                self.intercept_response(resp).await;
+            }
+            let Some(next) = next.filter(|_| self.retry.should_retry(&result)) else {
+                return result.map_err(send_error);
+            };
+
+            let delay = match &result {
+                Ok(resp) => retry_after(resp),
+                Err(_) => None,
+            }
+            .unwrap_or_else(|| self.retry.delay(attempt));
+            match &result {
+                Ok(resp) => debug!(
+                    "request to {} failed with status {}, retrying in {:?}",
+                    resp.url(),
+                    resp.status().as_u16(),
+                    delay
+                ),
+                Err(error) => debug!("request failed: {}, retrying in {:?}", error, delay),
+            }
+            tokio::time::sleep(delay).await;
+
+            attempt += 1;
+            req = next;
+        }
+    }
+
+    /// Waits until fewer than `max_concurrent` operations are in flight. The
+    /// operation counts until the permit is dropped
+    async fn concurrency_permit(&self) -> Option<OwnedSemaphorePermit> {
+        let semaphore = self.concurrency.clone()?;
+        // The semaphore is never closed
+        semaphore.acquire_owned().await.ok()
+    }
+
+    async fn wait_for_rate_limit(&self) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+    }
+
+    /// Fails if establishing a stream takes longer than the handshake timeout,
+    /// or the request timeout if there's none
+    async fn handshake<F, T>(&self, handshake: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        if let Some(timeout) = self.handshake_timeout {
+            return tokio::time::timeout(timeout, handshake)
+                .await
+                .map_err(|_| Error::HandshakeTimeout)?;
+        }
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, handshake)
+                .await
+                .map_err(|_| Error::TimeoutError)?,
+            None => handshake.await,
+        }
+    }
+
+    /// Sends a streaming request and waits for the response headers
+    async fn connect(&self, subpath: &str, req: RequestBuilder) -> Result<reqwest::Response> {
+        let req = self.intercept_request(req).await;
+        self.wait_for_rate_limit().await;
+
+        // The timeout can't be set on the request itself, since it would then
+        // cover reading the whole stream. Only wait for the headers instead.
+        let resp = self
+            .handshake(async { req.send().await.map_err(send_error) })
+            .await?;
+
+        Span::current().record("status_code", resp.status().as_u16());
+        self.intercept_response(&resp).await;
+
+        let status = resp.status();
+        if !status.is_success() {
+            error!(
+                "subscription/live query to {} failed with status: {}",
+                subpath,
+                status.as_u16()
+            );
+            return Err(Error::InvalidHTTPStatusCodeError(status.as_u16()));
+        }
+
+        Ok(resp)
+    }
+
+    /// Runs the operation unless the client is shut down first, in which case
+    /// it fails with [`Error::Cancelled`]
+    async fn until_shutdown<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        #[cfg(feature = "cancellation")]
+        if let Some(shutdown) = &self.shutdown {
+            let cancelled = std::pin::pin!(shutdown.cancelled());
+            return match future::select(std::pin::pin!(operation), cancelled).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => Err(Error::Cancelled),
+            };
+        }
+        operation.await
+    }
+
+    fn metrics(&self, subpath: &str, operation_type: OperationType) -> OperationMetrics {
+        OperationMetrics::new(self.on_metric.clone(), subpath, operation_type)
+    }
+
+    async fn intercept_request(&self, mut req: RequestBuilder) -> RequestBuilder {
+        for interceptor in &self.interceptors {
+            req = interceptor.on_request(req).await;
+        }
+        req
+    }
+
+    async fn intercept_response(&self, resp: &reqwest::Response) {
+        for interceptor in &self.interceptors {
+            interceptor.on_response(resp).await;
+        }
+    }
+
+    pub async fn query<P, I, R>(&self, subpath: P, input: I) -> Result<R>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let (data, _) = self.query_with_response(subpath, input).await?;
+        Ok(data)
+    }
+
+    /// Like [`Client::query`], but also returns a handle to cancel the query
+    /// with, e.g. from an event handler. Aborting it drops the request and
+    /// the future resolves to [`Error::Cancelled`]
+    pub fn query_abortable<'a, P, I, R>(
+        &'a self,
+        subpath: P,
+        input: I,
+    ) -> (impl Future<Output = Result<R>> + 'a, AbortHandle)
+    where
+        P: AsRef<str> + 'a,
+        I: Serialize + 'a,
+        R: for<'de> Deserialize<'de> + 'a,
+    {
+        let (handle, abort) = AbortHandle::new_pair();
+        let query = Abortable::new(self.query(subpath, input), abort)
+            .map(|result| result.unwrap_or(Err(Error::Cancelled)));
+        (query, handle)
+    }
+
+    /// Like [`Client::query`], but also returns the headers of the response,
+    /// e.g. the `X-Request-Id` the server assigned to the request
+    pub async fn query_with_response<P, I, R>(&self, subpath: P, input: I) -> Result<(R, HeaderMap)>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let options = RequestOptions::default();
+        self.fetch_data(subpath.as_ref(), Some(input), &options)
+            .await
+    }
+
+    /// Like [`Client::query`], but also sends the given query parameters
+    pub async fn query_with_params<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+        params: &[(&str, &str)],
+    ) -> Result<R>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let options = RequestOptions {
+            query_params: params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        self.query_with_options(subpath, input, options).await
+    }
+
+    /// Like [`Client::query`], but sent with the given method. With any method
+    /// but GET, the variables are sent as the body instead of in the URL
+    pub async fn query_with_method<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+        method: Method,
+    ) -> Result<R>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let options = RequestOptions {
+            method: Some(method),
+            ..Default::default()
+        };
+        self.query_with_options(subpath, input, options).await
+    }
+
+    /// Like [`Client::query`], with settings for this query only
+    pub async fn query_with_options<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+        options: RequestOptions,
+    ) -> Result<R>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let (data, _) = self
+            .fetch_data(subpath.as_ref(), Some(input), &options)
+            .await?;
+        Ok(data)
+    }
+
+    /// Like [`Client::query`], for operations without input. No variables are
+    /// sent at all, instead of an empty object
+    pub async fn query_no_input<P, R>(&self, subpath: P) -> Result<R>
+    where
+        P: AsRef<str>,
+        R: for<'de> Deserialize<'de>,
+    {
+        let options = RequestOptions::default();
+        let (data, _) = self
+            .fetch_data(subpath.as_ref(), None::<()>, &options)
+            .await?;
+        Ok(data)
+    }
+
+    async fn fetch_data<I, R>(
+        &self,
+        subpath: &str,
+        input: Option<I>,
+        options: &RequestOptions,
+    ) -> Result<(R, HeaderMap)>
+    where
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let mut metrics = self.metrics(subpath, OperationType::Query);
+        let query = async {
+            let resp = self.fetch_query(subpath, input, options).await?;
+
+            let data = record_outcome(resp.decode(subpath))?;
+            Ok((data, resp.headers))
+        }
+        .instrument(operation_span!("wundergraph.query", "query", subpath));
+        let result = self
+            .until_shutdown(until_deadline(query, options.deadline))
+            .await;
+        metrics.observe(&result);
+        result
+    }
+
+    /// Like [`Client::query`], but returns the data resolved so far even if
+    /// the response also contains errors. It only fails with
+    /// [`Error::ResponseError`] if the response contains no data at all
+    pub async fn query_partial<P, I, R>(&self, subpath: P, input: I) -> Result<PartialResponse<R>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let subpath = subpath.as_ref();
+        let mut metrics = self.metrics(subpath, OperationType::Query);
+        let result = self
+            .until_shutdown(
+                async {
+                    let options = RequestOptions::default();
+                    let resp = self.fetch_query(subpath, Some(input), &options).await?;
+
+                    resp.check_content_type(subpath)?;
+                    let result = decode_partial_bytes(subpath, resp.status, &resp.body);
+                    let result = with_request_id(result, request_id(&resp.headers).as_deref());
+                    record_outcome(result)
+                }
+                .instrument(operation_span!("wundergraph.query", "query", subpath)),
+            )
+            .await;
+        metrics.observe(&result);
+        result
+    }
+
+    /// Like [`Client::query`], but decodes the response with `decode`, which
+    /// is given the status code and the whole body, including the `data` and
+    /// `errors` fields. This allows decoding with custom settings, e.g. with
+    /// serde_json's `arbitrary_precision` or `raw_value` features
+    pub async fn query_with<P, I, R, F>(&self, subpath: P, input: I, decode: F) -> Result<R>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        F: FnOnce(StatusCode, &[u8]) -> Result<R>,
+    {
+        let subpath = subpath.as_ref();
+        let mut metrics = self.metrics(subpath, OperationType::Query);
+        let result = self
+            .until_shutdown(
+                async {
+                    let options = RequestOptions::default();
+                    let resp = self.fetch_query(subpath, Some(input), &options).await?;
+
+                    resp.check_content_type(subpath)?;
+                    record_outcome(decode(resp.status, &resp.body))
+                }
+                .instrument(operation_span!("wundergraph.query", "query", subpath)),
+            )
+            .await;
+        metrics.observe(&result);
+        result
+    }
+
+    /// Runs a query using incremental delivery, for operations using `@defer`
+    /// or `@stream`. The server sends the response as `multipart/mixed`, whose
+    /// payloads are yielded as they arrive, starting with the initial one. If
+    /// the server responds with a plain JSON response instead, it's yielded
+    /// as the only payload
+    pub async fn query_incremental<P, I>(
+        &self,
+        subpath: P,
+        input: I,
+    ) -> Result<impl Stream<Item = Result<IncrementalPayload>>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+    {
+        let subpath = subpath.as_ref();
+        let mut metrics = self.metrics(subpath, OperationType::Query);
+        let connect = async {
+            let variables = self.serialize_variables(&input)?;
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, HeaderValue::from_static(INCREMENTAL_DELIVERY));
+            // Replaces the JSON Accept header instead of adding a second one
+            let req = self
+                .request(Method::GET, subpath)?
+                .query(&[(&self.param_names.variables, variables)])
+                .headers(headers);
+
+            debug!("incremental query: {:?}", req);
+
+            self.connect(subpath, req).await
+        }
+        .instrument(operation_span!("wundergraph.query", "query", subpath));
+        let result = self
+            .until_shutdown(async {
+                let permit = self.concurrency_permit().await;
+                Ok((connect.await?, permit))
+            })
+            .await;
+        metrics.observe(&result);
+        let (resp, permit) = result?;
+
+        let subpath = subpath.to_string();
+        let status = resp.status();
+        let idle_timeout = self.idle_timeout;
+        let max_bytes = self.max_response_bytes;
+        let log_bodies = self.log_response_bodies;
+        let stream = stream! {
+            let _permit = permit;
+            let Some(boundary) = multipart_boundary(resp.headers()) else {
+                match read_response(resp, max_bytes).await {
+                    Ok(resp) => match decode_part(status, &resp.body) {
+                        Ok(payloads) => {
+                            for payload in payloads {
+                                yield Ok(payload);
+                            }
+                        }
+                        Err(e) => yield Err(e),
+                    },
+                    Err(e) => yield Err(e),
+                }
+                return;
+            };
+
+            let mut parts = MultipartBuffer::new(&boundary);
+            let mut body = resp.bytes_stream();
+            while !parts.is_done() {
+                let chunk = match with_idle_timeout(body.next(), idle_timeout).await {
+                    Err(e) => {
+                        yield Err(e);
+                        break;
+                    }
+                    Ok(None) => break,
+                    Ok(Some(Ok(chunk))) => chunk,
+                    Ok(Some(Err(e))) => {
+                        yield Err(read_error(e));
+                        break;
+                    }
+                };
+                parts.push(&chunk);
+                while let Some(part) = parts.next_part() {
+                    if log_bodies {
+                        log_body(&subpath, status, &part);
+                    }
+                    match decode_part(status, &part) {
+                        Ok(payloads) => {
+                            for payload in payloads {
+                                yield Ok(payload);
+                            }
+                        }
+                        Err(e) => yield Err(e),
+                    }
+                }
+            }
+        };
+        let stream = stream.inspect(move |item| metrics.observe(item));
+        #[cfg(feature = "cancellation")]
+        let stream = until_shutdown(stream, self.shutdown.clone());
+        Ok(stream)
+    }
+
+    /// Like [`Client::query`], for operations whose data might be `null`,
+    /// e.g. a lookup which found nothing. `R` doesn't need to accept `null`
+    /// itself. Responses with GraphQL errors and no data still fail with
+    /// [`Error::ResponseError`]
+    pub async fn query_optional<P, I, R>(&self, subpath: P, input: I) -> Result<Option<R>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let response = self.query_partial(subpath, input).await?;
+        Ok(response.data)
+    }
+
+    /// Runs a mutation. Unlike queries, which pass their input in the
+    /// variables query parameter, mutations are sent as a POST request with
+    /// the input itself as the JSON body, which is what the WunderGraph server
+    /// expects. The body is intentionally not wrapped in an `input` object
+    pub async fn mutate<P, I, R>(&self, subpath: P, input: I) -> Result<R>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        self.send_mutation(subpath.as_ref(), input, &RequestOptions::default())
+            .await
+    }
+
+    /// Like [`Client::mutate`], with settings for this mutation only
+    pub async fn mutate_with_options<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+        options: RequestOptions,
+    ) -> Result<R>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        self.send_mutation(subpath.as_ref(), input, &options).await
+    }
+
+    async fn send_mutation<I, R>(
+        &self,
+        subpath: &str,
+        input: I,
+        options: &RequestOptions,
+    ) -> Result<R>
+    where
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let mut metrics = self.metrics(subpath, OperationType::Mutation);
+        let mutation = async {
+            let _permit = self.concurrency_permit().await;
+            let req = self.mutation_request(subpath, &input, options)?;
+
+            debug!("mutation: {:?}", req);
+
+            let resp = self.send(req, false).await?;
+
+            let (data, _) = record_outcome(self.decode_response(subpath, resp).await)?;
+            Ok(data)
+        }
+        .instrument(operation_span!("wundergraph.mutation", "mutation", subpath));
+        let result = self
+            .until_shutdown(until_deadline(mutation, options.deadline))
+            .await;
+        metrics.observe(&result);
+        result
+    }
+
+    /// Builds the request a mutation is sent with. The input is the body, it's
+    /// never sent in the URL
+    fn mutation_request<I>(
+        &self,
+        subpath: &str,
+        input: &I,
+        options: &RequestOptions,
+    ) -> Result<RequestBuilder>
+    where
+        I: Serialize,
+    {
+        let body = serde_json::to_vec(input)?;
+        let req = self
+            .request(Method::POST, subpath)?
+            .query(&self.request_params(options));
+        Ok(self.sign(req, &body).body(body))
+    }
+
+    /// Runs a mutation with file inputs, following the GraphQL multipart
+    /// request spec. Each file is given with the path of its field in the
+    /// input, with segments separated by dots, e.g. `avatar` or `files.0`.
+    /// These fields are set to `null` in the input, and the map part refers
+    /// the server to the file parts replacing them
+    #[cfg(feature = "multipart")]
+    pub async fn upload<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+        files: Vec<(String, reqwest::multipart::Part)>,
+    ) -> Result<R>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let subpath = subpath.as_ref();
+        let mut metrics = self.metrics(subpath, OperationType::Mutation);
+        let mutation = async {
+            let _permit = self.concurrency_permit().await;
+            let mut operations = serde_json::to_value(&input)?;
+            let mut map = serde_json::Map::new();
+            let mut parts = Vec::with_capacity(files.len());
+            for (ii, (path, part)) in files.into_iter().enumerate() {
+                set_null(&mut operations, &path);
+                map.insert(ii.to_string(), serde_json::json!([path]));
+                parts.push((ii.to_string(), part));
+            }
+            let mut form = reqwest::multipart::Form::new()
+                .text("operations", operations.to_string())
+                .text("map", serde_json::Value::Object(map).to_string());
+            for (name, part) in parts {
+                form = form.part(name, part);
+            }
+
+            // The form appends its content type, replace the JSON one with it
+            let content_type = format!("multipart/form-data; boundary={}", form.boundary());
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_str(&content_type)
+                    .map_err(|e| anyhow::anyhow!("invalid multipart content type: {}", e))?,
+            );
+            let req = self
+                .request(Method::POST, subpath)?
+                .multipart(form)
+                .headers(headers);
+
+            debug!("upload: {:?}", req);
+
+            let resp = self.send(req, false).await?;
+
+            let (data, _) = record_outcome(self.decode_response(subpath, resp).await)?;
+            Ok(data)
+        }
+        .instrument(operation_span!("wundergraph.mutation", "mutation", subpath));
+        let result = self.until_shutdown(mutation).await;
+        metrics.observe(&result);
+        result
+    }
+
+    /// Like [`Client::query`], but returns the data as untyped JSON
+    pub async fn query_raw<P, I>(&self, subpath: P, input: I) -> Result<serde_json::Value>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+    {
+        self.query(subpath, input).await
+    }
+
+    /// Like [`Client::query`], with the variables given as JSON, e.g. to use
+    /// field names which differ from those of an input type without adding
+    /// serde attributes to it. They're serialized like any other input, so
+    /// object keys are sorted and [`ClientOptions::pretty_variables`]
+    /// applies
+    pub async fn query_value<P, R>(&self, subpath: P, variables: serde_json::Value) -> Result<R>
+    where
+        P: AsRef<str>,
+        R: for<'de> Deserialize<'de>,
+    {
+        self.query(subpath, variables).await
+    }
+
+    /// Like [`Client::mutate`], but returns the data as untyped JSON
+    pub async fn mutate_raw<P, I>(&self, subpath: P, input: I) -> Result<serde_json::Value>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+    {
+        self.mutate(subpath, input).await
+    }
+
+    /// Runs several queries at once and returns their results in the same
+    /// order. The WunderGraph server doesn't support batching operations into
+    /// a single request, so each query is sent as its own request, all of
+    /// them concurrently
+    pub async fn batch(&self, requests: Vec<BatchRequest>) -> Vec<Result<serde_json::Value>> {
+        let queries = requests
+            .into_iter()
+            .map(|request| async move { self.query_raw(request.subpath, request.input).await });
+        future::join_all(queries).await
+    }
+
+    pub async fn subscribe<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+    ) -> Result<impl Stream<Item = Result<R>>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let (stream, _) = self.subscribe_abortable(subpath, input).await?;
+        Ok(stream)
+    }
+
+    /// Like [`Client::subscribe`], but also returns a handle to end the
+    /// subscription with. Aborting it closes the connection and ends the stream
+    pub async fn subscribe_abortable<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+    ) -> Result<(impl Stream<Item = Result<R>>, AbortHandle)>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let (stream, handle) = self
+            .open_subscription(
+                subpath.as_ref(),
+                Some(input),
+                &RequestOptions::default(),
+                decode_bytes,
+            )
+            .await?;
+        Ok((into_results(stream), handle))
+    }
+
+    /// Like [`Client::subscribe`], but yields [`StreamItem`]s, which tell
+    /// whether an error ended the stream. Over WebSockets and server-sent
+    /// events, the last item is `StreamEnded(None)` if the server completed
+    /// the subscription, and carries an error like [`Error::ConnectionClosed`]
+    /// if the connection was lost instead. Newline delimited JSON streams
+    /// have no completion marker, so a connection closed without an error
+    /// ends them with `StreamEnded(None)` either way
+    pub async fn subscribe_items<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+    ) -> Result<impl Stream<Item = StreamItem<R>>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let (stream, _) = self
+            .open_subscription(
+                subpath.as_ref(),
+                Some(input),
+                &RequestOptions::default(),
+                decode_bytes,
+            )
+            .await?;
+        Ok(stream)
+    }
+
+    /// Opens a WebSocket connection which many subscriptions can share,
+    /// instead of opening one connection per subscription. The connection is
+    /// driven by the returned future, which has to be polled for the
+    /// subscriptions to receive anything, e.g. by passing it to
+    /// `tokio::spawn`. It completes once the connection fails, or the manager
+    /// and all its subscriptions have been dropped. Reconnecting isn't
+    /// supported, open a new manager instead
+    #[cfg(feature = "websocket")]
+    pub async fn subscription_manager(
+        &self,
+    ) -> Result<(SubscriptionManager, impl Future<Output = ()>)> {
+        // Build a request just to reuse the headers it would be sent with
+        let headers = self.unchecked_request(Method::GET, "")?;
+        let headers = headers.build().map_err(send_error)?;
+        self.wait_for_rate_limit().await;
+        self.handshake(crate::websocket::subscription_manager(
+            self.clone(),
+            &self.websocket_url,
+            headers.headers().clone(),
+            self.idle_timeout,
+            self.skip_undecodable_frames,
+        ))
+        .await
+    }
+
+    /// Like [`Client::subscribe`], for operations without input. No variables
+    /// are sent at all, instead of an empty object
+    pub async fn subscribe_no_input<P, R>(
+        &self,
+        subpath: P,
+    ) -> Result<impl Stream<Item = Result<R>>>
+    where
+        P: AsRef<str>,
+        R: for<'de> Deserialize<'de>,
+    {
+        let (stream, _) = self
+            .open_subscription(
+                subpath.as_ref(),
+                None::<()>,
+                &RequestOptions::default(),
+                decode_bytes,
+            )
+            .await?;
+        Ok(into_results(stream))
     }
 
-    pub async fn query<P, I, R>(&self, subpath: P, input: I) -> Result<R>
+    /// Like [`Client::subscribe`], with settings for this subscription only.
+    /// The query parameters and operation hash aren't sent over WebSockets
+    pub async fn subscribe_with_options<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+        options: RequestOptions,
+    ) -> Result<impl Stream<Item = Result<R>>>
     where
         P: AsRef<str>,
         I: Serialize,
         R: for<'de> Deserialize<'de>,
     {
-        let subpath = subpath.as_ref();
-        let url = self
-            .url
-            .join(subpath)
-            .map_err(|e| anyhow::anyhow!("failed to parse url subpath: {}", e))?;
-
-        let data = serde_json::to_string(&input)?;
-
-        let req = self
-            .client
-            .get(url)
-            .query(&[("wg_variables", data)])
-            .query(&[("wg_app_hash", &self.application_hash)])
-            .header("Accept", "application/json")
-            .header("Content-Type", "application/json");
+        let (stream, _) = self
+            .open_subscription(subpath.as_ref(), Some(input), &options, decode_bytes)
+            .await?;
+        Ok(into_results(stream))
+    }
 
-        debug!("query: {:?}", req);
+    async fn open_subscription<I, R, D>(
+        &self,
+        subpath: &str,
+        input: Option<I>,
+        options: &RequestOptions,
+        decode: D,
+    ) -> Result<(impl Stream<Item = StreamItem<R>>, AbortHandle)>
+    where
+        I: Serialize,
+        D: FnMut(&str, StatusCode, &[u8]) -> Result<R>,
+    {
+        let mut metrics = self.metrics(subpath, OperationType::Subscription);
+        let (handle, abort) = AbortHandle::new_pair();
+        // Only the initial connection is covered by the span
+        let connect = async {
+            #[cfg(feature = "websocket")]
+            if self.subscription_transport == SubscriptionTransport::WebSocket {
+                // Build a request just to reuse the headers it would be sent with
+                let headers = self.request(Method::GET, subpath)?;
+                let headers = headers.build().map_err(send_error)?;
+                let variables = input.map(|input| serde_json::to_value(&input));
+                let variables = variables.transpose()?;
+                self.wait_for_rate_limit().await;
+                let stream = self
+                    .handshake(crate::websocket::subscribe(
+                        &self.websocket_url,
+                        headers.headers().clone(),
+                        subpath,
+                        variables,
+                        self.idle_timeout,
+                        abort,
+                        decode,
+                    ))
+                    .await?;
+                let stream = skip_undecodable_frames(stream, subpath, self.skip_undecodable_frames);
+                return Ok(Either::Left(stream));
+            }
 
-        let resp = req
-            .send()
-            .await
-            .map_err(|e| anyhow::anyhow!("failed to send request: {}", e))?;
+            let mut params = self.request_params(options);
+            if options.sse || self.subscription_transport == SubscriptionTransport::ServerSentEvents
+            {
+                params.push((self.param_names.sse.clone(), "true".to_string()));
+            }
+            let stream =
+                streaming_request(self, subpath, input, false, params, abort, decode).await?;
+            let stream = skip_undecodable_frames(stream, subpath, self.skip_undecodable_frames);
+            #[cfg(feature = "websocket")]
+            let stream = Either::Right(stream);
+            Ok::<_, Error>(stream)
+        }
+        .instrument(operation_span!(
+            "wundergraph.subscription",
+            "subscription",
+            subpath
+        ));
+        let result = self
+            .until_shutdown(until_deadline(
+                async {
+                    let permit = self.concurrency_permit().await;
+                    Ok((connect.await?, permit))
+                },
+                options.deadline,
+            ))
+            .await;
+        metrics.observe(&result);
+        let (stream, permit) = result?;
+        let stream = with_permit(with_metrics(stream, metrics), permit);
+        #[cfg(feature = "cancellation")]
+        let stream = until_shutdown(stream, self.shutdown.clone());
+        Ok((stream, handle))
+    }
 
-        decode_response(subpath, resp).await
+    pub async fn live_query<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+    ) -> Result<impl Stream<Item = Result<R>>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let (stream, _) = self.live_query_abortable(subpath, input).await?;
+        Ok(stream)
     }
 
-    pub async fn mutate<P, I, R>(&self, subpath: P, input: I) -> Result<R>
+    /// Like [`Client::live_query`], but also returns a handle to end the live
+    /// query with. Aborting it closes the connection and ends the stream
+    pub async fn live_query_abortable<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+    ) -> Result<(impl Stream<Item = Result<R>>, AbortHandle)>
     where
         P: AsRef<str>,
         I: Serialize,
         R: for<'de> Deserialize<'de>,
     {
-        let subpath = subpath.as_ref();
-        let url = self
-            .url
-            .join(subpath)
-            .map_err(|e| anyhow::anyhow!("failed to parse url subpath: {}", e))?;
+        let (stream, handle) = self
+            .open_live_query(
+                subpath.as_ref(),
+                Some(input),
+                &RequestOptions::default(),
+                false,
+                decode_bytes,
+            )
+            .await?;
+        Ok((into_results(stream), handle))
+    }
 
-        let req = self
-            .client
-            .post(url)
-            .query(&[("wg_app_hash", &self.application_hash)])
-            .json(&input)
-            .header("Accept", "application/json");
+    /// Like [`Client::live_query`], but yields [`StreamItem`]s, which tell
+    /// whether an error ended the stream
+    pub async fn live_query_items<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+    ) -> Result<impl Stream<Item = StreamItem<R>>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let (stream, _) = self
+            .open_live_query(
+                subpath.as_ref(),
+                Some(input),
+                &RequestOptions::default(),
+                false,
+                decode_bytes,
+            )
+            .await?;
+        Ok(stream)
+    }
 
-        debug!("mutation: {:?}", req);
+    /// Like [`Client::live_query`], for operations without input. No
+    /// variables are sent at all, instead of an empty object
+    pub async fn live_query_no_input<P, R>(
+        &self,
+        subpath: P,
+    ) -> Result<impl Stream<Item = Result<R>>>
+    where
+        P: AsRef<str>,
+        R: for<'de> Deserialize<'de>,
+    {
+        let (stream, _) = self
+            .open_live_query(
+                subpath.as_ref(),
+                None::<()>,
+                &RequestOptions::default(),
+                false,
+                decode_bytes,
+            )
+            .await?;
+        Ok(into_results(stream))
+    }
 
-        let resp = req
-            .send()
-            .await
-            .map_err(|e| anyhow::anyhow!("failed to send request: {}", e))?;
+    /// Like [`Client::subscribe`], but yields the frames as they were
+    /// received, without decoding them, e.g. to inspect or record them. The
+    /// response's status is still checked. Over WebSockets, the frames are
+    /// the payloads of the `next` messages
+    pub async fn subscribe_raw<P, I>(
+        &self,
+        subpath: P,
+        input: I,
+    ) -> Result<impl Stream<Item = Result<Bytes>>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+    {
+        let (stream, _) = self
+            .open_subscription(
+                subpath.as_ref(),
+                Some(input),
+                &RequestOptions::default(),
+                raw_frame,
+            )
+            .await?;
+        Ok(into_results(stream))
+    }
 
-        decode_response(subpath, resp).await
+    /// Like [`Client::live_query`], but yields the frames as they were
+    /// received, without decoding them, see [`Client::subscribe_raw`]
+    pub async fn live_query_raw<P, I>(
+        &self,
+        subpath: P,
+        input: I,
+    ) -> Result<impl Stream<Item = Result<Bytes>>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+    {
+        let (stream, _) = self
+            .open_live_query(
+                subpath.as_ref(),
+                Some(input),
+                &RequestOptions::default(),
+                false,
+                raw_frame,
+            )
+            .await?;
+        Ok(into_results(stream))
     }
 
-    pub async fn subscribe<P, I, R>(
+    /// Like [`Client::live_query`], with settings for this live query only
+    pub async fn live_query_with_options<P, I, R>(
         &self,
         subpath: P,
         input: I,
+        options: RequestOptions,
     ) -> Result<impl Stream<Item = Result<R>>>
     where
         P: AsRef<str>,
         I: Serialize,
         R: for<'de> Deserialize<'de>,
     {
-        streaming_request(self, subpath.as_ref(), &self.application_hash, input, false).await
+        let (stream, _) = self
+            .open_live_query(subpath.as_ref(), Some(input), &options, false, decode_bytes)
+            .await?;
+        Ok(into_results(stream))
     }
 
-    pub async fn live_query<P, I, R>(
+    /// Starts a live query, decoding its frames with `decode`. With
+    /// `json_patch`, the server is asked to send JSON patches instead of full
+    /// responses after the first one
+    async fn open_live_query<I, R, D>(
+        &self,
+        subpath: &str,
+        input: Option<I>,
+        options: &RequestOptions,
+        json_patch: bool,
+        decode: D,
+    ) -> Result<(impl Stream<Item = StreamItem<R>>, AbortHandle)>
+    where
+        I: Serialize,
+        D: FnMut(&str, StatusCode, &[u8]) -> Result<R>,
+    {
+        let mut metrics = self.metrics(subpath, OperationType::LiveQuery);
+        let (handle, abort) = AbortHandle::new_pair();
+        let mut params = self.request_params(options);
+        if json_patch {
+            params.push((self.param_names.json_patch.clone(), "true".to_string()));
+        }
+        if options.sse {
+            params.push((self.param_names.sse.clone(), "true".to_string()));
+        }
+        // Only the initial connection is covered by the span
+        let connect =
+            streaming_request(self, subpath, input, true, params, abort, decode).instrument(
+                operation_span!("wundergraph.live_query", "live_query", subpath),
+            );
+        let result = self
+            .until_shutdown(until_deadline(
+                async {
+                    let permit = self.concurrency_permit().await;
+                    Ok((connect.await?, permit))
+                },
+                options.deadline,
+            ))
+            .await;
+        metrics.observe(&result);
+        let (stream, permit) = result?;
+        let stream = skip_undecodable_frames(stream, subpath, self.skip_undecodable_frames);
+        let stream = with_permit(with_metrics(stream, metrics), permit);
+        #[cfg(feature = "cancellation")]
+        let stream = until_shutdown(stream, self.shutdown.clone());
+        Ok((stream, handle))
+    }
+
+    /// Like [`Client::live_query`], but asks the server to send JSON patches
+    /// instead of full responses after the first one. The updates are
+    /// yielded as they're received, without being applied
+    #[cfg(feature = "json-patch")]
+    pub async fn live_query_patches<P, I>(
+        &self,
+        subpath: P,
+        input: I,
+    ) -> Result<impl Stream<Item = Result<LiveQueryUpdate>>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+    {
+        let decode =
+            |_: &str, status: StatusCode, data: &[u8]| LiveQueryUpdate::decode(status, data);
+        let (stream, _) = self
+            .open_live_query(
+                subpath.as_ref(),
+                Some(input),
+                &RequestOptions::default(),
+                true,
+                decode,
+            )
+            .await?;
+        Ok(into_results(stream))
+    }
+
+    /// Like [`Client::live_query`], but asks the server to send JSON patches
+    /// instead of full responses after the first one. The patches are applied
+    /// to the previous response, so every item is the complete result
+    #[cfg(feature = "json-patch")]
+    pub async fn live_query_patched<P, I, R>(
         &self,
         subpath: P,
         input: I,
@@ -132,111 +1932,706 @@ impl Client {
         I: Serialize,
         R: for<'de> Deserialize<'de>,
     {
-        streaming_request(self, subpath.as_ref(), &self.application_hash, input, true).await
+        let mut response = PatchedResponse::default();
+        let decode = move |subpath: &str, status: StatusCode, data: &[u8]| {
+            response.decode(subpath, status, data)
+        };
+        let (stream, _) = self
+            .open_live_query(
+                subpath.as_ref(),
+                Some(input),
+                &RequestOptions::default(),
+                true,
+                decode,
+            )
+            .await?;
+        Ok(into_results(stream))
+    }
+
+    /// Runs a typed query or mutation
+    pub async fn execute<O: Operation>(&self, input: O::Input) -> Result<O::Output> {
+        match O::KIND {
+            OperationKind::Query => self.query(O::PATH, input).await,
+            OperationKind::Mutation => self.mutate(O::PATH, input).await,
+        }
+    }
+
+    /// Runs a typed query as a live query
+    pub async fn execute_live<O: Operation>(
+        &self,
+        input: O::Input,
+    ) -> Result<impl Stream<Item = Result<O::Output>>> {
+        self.live_query(O::PATH, input).await
+    }
+
+    /// Runs a typed subscription
+    pub async fn execute_subscription<O: SubscriptionOperation>(
+        &self,
+        input: O::Input,
+    ) -> Result<impl Stream<Item = Result<O::Output>>> {
+        self.subscribe(O::PATH, input).await
     }
 }
 
-async fn decode_response<T>(subpath: &str, resp: reqwest::Response) -> Result<T>
+/// Drops the frames of the stream which couldn't be decoded if `skip` is set,
+/// see [`ClientOptions::skip_undecodable_frames`]
+pub(crate) fn skip_undecodable_frames<S, R>(
+    stream: S,
+    subpath: &str,
+    skip: bool,
+) -> impl Stream<Item = StreamItem<R>>
 where
-    T: for<'de> Deserialize<'de>,
+    S: Stream<Item = StreamItem<R>>,
+{
+    let subpath = subpath.to_string();
+    stream.filter(move |item| {
+        let keep = match item {
+            StreamItem::InvalidFrame(e) if skip => {
+                error!("skipping undecodable frame from {}: {}", subpath, e);
+                false
+            }
+            _ => true,
+        };
+        future::ready(keep)
+    })
+}
+
+/// Returns a frame of a stream as it is, see [`Client::subscribe_raw`]
+fn raw_frame(_subpath: &str, _status: StatusCode, frame: &[u8]) -> Result<Bytes> {
+    Ok(Bytes::copy_from_slice(frame))
+}
+
+/// Records on the current span whether the operation failed with a GraphQL
+/// error
+fn record_outcome<T>(result: Result<T>) -> Result<T> {
+    let graphql_error = matches!(result, Err(Error::ResponseError(_)));
+    Span::current().record("graphql_error", graphql_error);
+    result
+}
+
+/// Keeps the metrics of a stream up to date with the items it yields, they're
+/// reported once the stream ends or is dropped
+fn with_metrics<S, T>(stream: S, mut metrics: OperationMetrics) -> impl Stream<Item = StreamItem<T>>
+where
+    S: Stream<Item = StreamItem<T>>,
+{
+    stream! {
+        let mut stream = std::pin::pin!(stream);
+        while let Some(item) = stream.next().await {
+            metrics.observe_item(&item);
+            yield item;
+        }
+    }
+}
+
+/// Keeps the concurrency permit of a stream until it ends or is dropped
+fn with_permit<S>(stream: S, permit: Option<OwnedSemaphorePermit>) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
+{
+    stream! {
+        let _permit = permit;
+        let mut stream = std::pin::pin!(stream);
+        while let Some(item) = stream.next().await {
+            yield item;
+        }
+    }
+}
+
+/// Converts a stream of [`StreamItem`]s into the stream of `Result`s returned
+/// by the public API
+fn into_results<S, T>(stream: S) -> impl Stream<Item = Result<T>>
+where
+    S: Stream<Item = StreamItem<T>>,
+{
+    stream.filter_map(|item| future::ready(item.into_result()))
+}
+
+/// Ends the stream once the client is shut down. Returning drops the inner
+/// stream, which closes its connection
+#[cfg(feature = "cancellation")]
+fn until_shutdown<S>(
+    stream: S,
+    shutdown: Option<tokio_util::sync::CancellationToken>,
+) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
 {
+    stream! {
+        let mut stream = std::pin::pin!(stream);
+        loop {
+            let item = match &shutdown {
+                Some(shutdown) => {
+                    let cancelled = std::pin::pin!(shutdown.cancelled());
+                    match future::select(stream.next(), cancelled).await {
+                        Either::Left((item, _)) => item,
+                        Either::Right(_) => return,
+                    }
+                }
+                None => stream.next().await,
+            };
+            let Some(item) = item else {
+                return;
+            };
+            yield item;
+        }
+    }
+}
+
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Builds the HTTP client used when none is passed in the options
+fn build_http_client(options: &ClientOptions) -> reqwest::Result<reqwest::Client> {
+    let user_agent = match &options.user_agent {
+        Some(user_agent) => user_agent.as_str(),
+        None => DEFAULT_USER_AGENT,
+    };
+    let mut builder = reqwest::Client::builder().user_agent(user_agent);
+    if options.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(max) = options.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    if let Some(timeout) = options.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(timeout);
+    }
+    if let Some(timeout) = options.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(url) = &options.proxy {
+        let mut proxy = reqwest::Proxy::all(url.clone())?;
+        if let Some((username, password)) = &options.proxy_auth {
+            proxy = proxy.basic_auth(username, password);
+        }
+        builder = builder.proxy(proxy);
+    }
+    builder = builder.redirect(options.redirect_policy.into());
+    #[cfg(feature = "cookies")]
+    {
+        builder = match &options.cookie_jar {
+            Some(jar) => builder.cookie_provider(jar.clone()),
+            None => builder.cookie_store(options.cookie_store),
+        };
+    }
+    #[cfg(feature = "tls")]
+    {
+        for certificate in &options.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+        if let Some(identity) = &options.identity {
+            builder = builder.identity(identity.clone());
+        }
+        builder = builder.danger_accept_invalid_certs(options.danger_accept_invalid_certs);
+    }
+    #[cfg(feature = "gzip")]
+    let builder = builder.gzip(true);
+    #[cfg(feature = "brotli")]
+    let builder = builder.brotli(true);
+    builder.build()
+}
+
+fn send_error(error: reqwest::Error) -> Error {
+    if error.is_timeout() {
+        return Error::TimeoutError;
+    }
+    Error::RequestError(error)
+}
+
+/// Errors can't be cloned, so the callers sharing a deduplicated query
+/// besides the one which sent it get their own copy of the error. Errors
+/// which can't be copied, like those of reqwest, are copied as their message
+fn shared_error(error: &Error) -> Error {
+    match error {
+        Error::TimeoutError => Error::TimeoutError,
+        Error::DeadlineExceeded => Error::DeadlineExceeded,
+        Error::Cancelled => Error::Cancelled,
+        Error::InvalidHTTPStatusCodeError(status) => Error::InvalidHTTPStatusCodeError(*status),
+        Error::UrlError(error) => Error::UrlError(*error),
+        Error::UnknownOperation(subpath) => Error::UnknownOperation(subpath.clone()),
+        Error::ResponseTooLarge { limit } => Error::ResponseTooLarge { limit: *limit },
+        Error::IncompleteResponse { expected, received } => Error::IncompleteResponse {
+            expected: *expected,
+            received: *received,
+        },
+        error => anyhow::anyhow!("{}", error).into(),
+    }
+}
+
+/// Reads the whole body of the response in chunks. With a limit, reading
+/// stops as soon as the body grows past it
+async fn read_response(
+    mut resp: reqwest::Response,
+    max_bytes: Option<usize>,
+) -> Result<RawResponse> {
     let status = resp.status();
-    let data = resp
-        .bytes()
-        .await
-        .map_err(|e| anyhow::anyhow!("error reading response: {}", e))?;
-    decode_bytes(subpath, status, &data)
+    // Take the headers before reading the body consumes the response
+    let headers = std::mem::take(resp.headers_mut());
+    // Unknown for chunked and decompressed bodies
+    let content_length = resp.content_length();
+    if let Some(limit) = max_bytes {
+        if content_length.is_some_and(|len| len > limit as u64) {
+            return Err(Error::ResponseTooLarge { limit });
+        }
+    }
+    let mut data = Vec::new();
+    loop {
+        let chunk = match resp.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(error) => return Err(incomplete_body(error, content_length, data.len())),
+        };
+        if let Some(limit) = max_bytes.filter(|limit| data.len() + chunk.len() > *limit) {
+            return Err(Error::ResponseTooLarge { limit });
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(RawResponse {
+        status,
+        headers,
+        body: data.into(),
+    })
+}
+
+/// Returns the error of a failed body read. With a known length, the body is
+/// read only up to it, so a failure before it's reached means the connection
+/// was closed early
+fn incomplete_body(error: reqwest::Error, expected: Option<u64>, received: usize) -> Error {
+    match expected {
+        Some(expected) if !error.is_timeout() => {
+            error!(
+                "received {} bytes of a response with a length of {} bytes: {}",
+                received, expected, error
+            );
+            Error::IncompleteResponse { expected, received }
+        }
+        _ => read_error(error),
+    }
+}
+
+fn read_error(error: reqwest::Error) -> Error {
+    if error.is_timeout() {
+        return Error::TimeoutError;
+    }
+    anyhow::anyhow!("error reading response: {}", error).into()
+}
+
+fn log_body(subpath: &str, status: StatusCode, body: &[u8]) {
+    trace!(
+        "response from {} with status {}: {}",
+        subpath,
+        status.as_u16(),
+        truncate_body(body)
+    );
 }
 
-fn decode_bytes<T>(subpath: &str, status_code: reqwest::StatusCode, data: &[u8]) -> Result<T>
+pub(crate) fn decode_bytes<T>(
+    subpath: &str,
+    status_code: reqwest::StatusCode,
+    data: &[u8],
+) -> Result<T>
 where
     T: for<'de> Deserialize<'de>,
 {
     // Try to decode the response first. Since even values with non-200
     // HTTP codes might contain useful error messages
-    match serde_json::from_slice::<Response<T>>(data) {
-        Ok(response) => {
-            // Response was decoded. If it's a GraphQL error, insert the status code
-            match response {
-                Response::Data(data) => Ok(data.data),
-                Response::Error(error) => Err(ResponseError {
-                    status_code: status_code.as_u16(),
-                    code: error.code,
-                    errors: error.errors,
-                }
-                .into()),
-            }
+    let response = serde_json::from_slice::<ResponseBody<T>>(data)
+        .map_err(|error| decode_error(subpath, status_code, data, error))?;
+    match response {
+        ResponseBody {
+            data: Some(data), ..
+        } => Ok(data),
+        // Without data, it's a GraphQL error. Insert the status code
+        ResponseBody {
+            data: None,
+            code,
+            errors: Some(errors),
+        } => Err(ResponseError {
+            status_code: status_code.as_u16(),
+            code,
+            errors,
+            request_id: None,
         }
-        Err(error) => {
-            if !status_code.is_success() {
-                error!(
-                    "request to {} failed with status: {}",
-                    subpath,
-                    status_code.as_u16()
-                );
-                return Err(Error::InvalidHTTPStatusCodeError(status_code.as_u16()));
-            }
-            Err(error.into())
+        .into()),
+        // Neither data nor errors, which is only valid if `T` accepts `null`
+        ResponseBody {
+            data: None,
+            errors: None,
+            ..
+        } => T::deserialize(serde_json::Value::Null)
+            .map_err(|error| decode_error(subpath, status_code, data, error)),
+    }
+}
+
+/// Returns the error for a response body which couldn't be decoded
+fn decode_error(
+    subpath: &str,
+    status_code: reqwest::StatusCode,
+    data: &[u8],
+    error: serde_json::Error,
+) -> Error {
+    if std::str::from_utf8(data).is_err() {
+        error!(
+            "response from {} with status {} isn't valid UTF-8",
+            subpath,
+            status_code.as_u16()
+        );
+        return Error::InvalidResponseEncoding {
+            status: status_code.as_u16(),
+        };
+    }
+    if !status_code.is_success() {
+        error!(
+            "request to {} failed with status: {}",
+            subpath,
+            status_code.as_u16()
+        );
+        return Error::InvalidHTTPStatusCodeError(status_code.as_u16());
+    }
+    Error::DeserializationError {
+        source: error,
+        status: status_code.as_u16(),
+        body: truncate_body(data),
+    }
+}
+
+fn decode_partial_bytes<T>(
+    subpath: &str,
+    status_code: reqwest::StatusCode,
+    data: &[u8],
+) -> Result<PartialResponse<T>>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    match serde_json::from_slice::<ResponseBody<T>>(data) {
+        Ok(ResponseBody {
+            data: None,
+            code,
+            errors: Some(errors),
+        }) if !errors.is_empty() => Err(ResponseError {
+            status_code: status_code.as_u16(),
+            code,
+            errors,
+            request_id: None,
         }
+        .into()),
+        Ok(response) => Ok(PartialResponse {
+            data: response.data,
+            errors: response.errors.unwrap_or_default(),
+        }),
+        Err(error) => Err(decode_error(subpath, status_code, data, error)),
     }
 }
 
-async fn streaming_request<T, U>(
+async fn streaming_request<T, U, D>(
     client: &Client,
     subpath: &str,
-    application_hash: &str,
-    input: T,
+    input: Option<T>,
     live: bool,
-) -> Result<impl Stream<Item = Result<U>>>
+    params: Vec<(String, String)>,
+    abort: AbortRegistration,
+    mut decode: D,
+) -> Result<impl Stream<Item = StreamItem<U>>>
 where
     T: Serialize,
-    U: for<'de> Deserialize<'de>,
+    D: FnMut(&str, StatusCode, &[u8]) -> Result<U>,
 {
-    let url = client
-        .url
-        .join(subpath)
-        .map_err(|e| anyhow::anyhow!("failed to parse url subpath: {}", e))?;
+    let req = client.request(Method::GET, subpath)?;
+    let req = match input {
+        Some(input) => {
+            let data = client.serialize_variables(&input)?;
+            req.query(&[(&client.param_names.variables, data)])
+        }
+        None => req,
+    };
 
-    let data = serde_json::to_string(&input)?;
+    let req = if live {
+        req.query(&[(&client.param_names.live, true)])
+    } else {
+        req
+    };
 
-    let req = client
-        .client
-        .get(url)
-        .query(&[("wg_variables", data)])
-        .query(&[("wg_app_hash", application_hash)])
-        .header("Accept", "application/json")
-        .header("Content-Type", "application/json");
+    // Server-sent events are asked for through their parameter
+    let sse = params
+        .iter()
+        .any(|(name, _)| *name == client.param_names.sse);
+    let req = req.query(&params);
 
-    let req = if live {
-        req.query(&[("wg_live", true)])
+    let req = if sse {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(EVENT_STREAM));
+        // Replaces the JSON Accept header instead of adding a second one
+        req.headers(headers)
     } else {
         req
     };
 
     debug!("Request: {:?}", req);
 
-    let resp = req
-        .send()
-        .await
-        .map_err(|e| anyhow::anyhow!("failed to send request: {}", e))?;
+    let resp = client.connect(subpath, stream_request(&req)?).await?;
 
-    let status = resp.status();
-    if !status.is_success() {
-        error!(
-            "subscription/live query failed with status: {}",
-            resp.status().as_u16()
-        );
-        return Err(Error::InvalidHTTPStatusCodeError(resp.status().as_u16()));
-    }
+    let subpath = String::from(subpath);
+    let client = client.clone();
+    let stream = stream! {
+        let mut abort = Abortable::new(future::pending::<()>(), abort);
+        let mut resp = resp;
+        let mut attempt = 0;
+        loop {
+            let status = resp.status();
+            let request_id = request_id(resp.headers());
+            let mut frames = if sse || is_event_stream(resp.headers()) {
+                FrameBuffer::event_stream()
+            } else {
+                FrameBuffer::default()
+            };
+            let mut resp_stream = resp.bytes_stream();
+            let mut last_error: Option<Error> = None;
+            loop {
+                // Returning drops the response, which closes the connection
+                let next = with_idle_timeout(resp_stream.next(), client.idle_timeout);
+                let data = match until_aborted(next, &mut abort).await {
+                    None => return,
+                    Some(Err(error)) => {
+                        last_error = Some(error);
+                        break;
+                    }
+                    Some(Ok(None)) => break,
+                    Some(Ok(Some(Ok(data)))) => data,
+                    Some(Ok(Some(Err(e)))) => {
+                        let error = anyhow::anyhow!("failed to read response: {}", e);
+                        last_error = Some(error.into());
+                        break;
+                    }
+                };
+                attempt = 0;
+                frames.push(&data);
+                while let Some(frame) = frames.next_frame() {
+                    if client.log_response_bodies {
+                        log_body(&subpath, status, &frame);
+                    }
+                    let result = decode(&subpath, status, &frame);
+                    yield StreamItem::from_frame(with_request_id(result, request_id.as_deref()));
+                }
+                if frames.is_completed() {
+                    break;
+                }
+            }
 
-    let mut resp_stream = resp.bytes_stream();
+            // A stream the server completed is never reconnected
+            let reconnect = client.reconnect.as_ref().filter(|_| !frames.is_completed());
+            let Some(reconnect) = reconnect else {
+                // Only event streams mark their end, otherwise a closed
+                // connection is all there is
+                let lost = frames.is_event_stream() && !frames.is_completed();
+                if last_error.is_none() {
+                    match frames.finish() {
+                        Ok(Some(frame)) => {
+                            if client.log_response_bodies {
+                                log_body(&subpath, status, &frame);
+                            }
+                            let result = decode(&subpath, status, &frame);
+                            yield StreamItem::from_frame(with_request_id(result, request_id.as_deref()));
+                        }
+                        Ok(None) => {}
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+                if last_error.is_none() && lost {
+                    last_error = Some(Error::ConnectionClosed);
+                }
+                yield StreamItem::StreamEnded(last_error);
+                break;
+            };
 
-    let subpath = String::from(subpath);
-    let stream = stream!(while let Some(item) = resp_stream.next().await {
-        // TODO: Handle chunking
-        let data = item.map_err(|e| anyhow::anyhow!("failed to read response: {}", e))?;
-        yield decode_bytes(&subpath, status, &data)
-    });
+            // Any partially received frame is lost, the new connection
+            // starts from scratch
+            resp = loop {
+                attempt += 1;
+                if attempt > reconnect.max_attempts {
+                    error!(
+                        "giving up reconnecting to {} after {} attempts",
+                        subpath, reconnect.max_attempts
+                    );
+                    let error = last_error.unwrap_or_else(|| anyhow::anyhow!("stream ended").into());
+                    yield StreamItem::StreamEnded(Some(error));
+                    return;
+                }
+                let reconnected = until_aborted(async {
+                    tokio::time::sleep(reconnect.delay(attempt)).await;
+                    debug!("reconnecting to {} (attempt {})", subpath, attempt);
+                    client.connect(&subpath, stream_request(&req)?).await
+                }, &mut abort).await;
+                match reconnected {
+                    None => return,
+                    Some(Ok(resp)) => break resp,
+                    Some(Err(error)) => last_error = Some(error),
+                }
+            };
+        }
+    };
 
     Ok(stream)
 }
+
+/// Runs the future unless the stream is aborted first, in which case it
+/// returns `None`
+pub(crate) async fn until_aborted<F>(
+    future: F,
+    abort: &mut Abortable<Pending<()>>,
+) -> Option<F::Output>
+where
+    F: Future,
+{
+    match future::select(std::pin::pin!(future), abort).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+/// Fails with [`Error::DeadlineExceeded`] if the operation isn't done by the
+/// deadline
+async fn until_deadline<F, T>(operation: F, deadline: Option<Instant>) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match deadline {
+        Some(deadline) => {
+            tokio::time::timeout_at(tokio::time::Instant::from_std(deadline), operation)
+                .await
+                .unwrap_or(Err(Error::DeadlineExceeded))
+        }
+        None => operation.await,
+    }
+}
+
+/// Fails with [`Error::IdleTimeout`] if the future doesn't complete in time
+pub(crate) async fn with_idle_timeout<F>(future: F, timeout: Option<Duration>) -> Result<F::Output>
+where
+    F: Future,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| Error::IdleTimeout),
+        None => Ok(future.await),
+    }
+}
+
+fn stream_request(req: &RequestBuilder) -> Result<RequestBuilder> {
+    req.try_clone()
+        .ok_or_else(|| anyhow::anyhow!("streaming request can't be cloned").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn errors_without_code_are_a_response_error() {
+        let body = br#"{"errors":[{"message":"not found"}]}"#;
+        let result = decode_bytes::<serde_json::Value>("Weather", StatusCode::OK, body);
+        match result {
+            Err(Error::ResponseError(error)) => {
+                assert_eq!(error.status_code, 200);
+                assert_eq!(error.code, None);
+                assert_eq!(error.errors.len(), 1);
+                assert_eq!(error.errors[0].message, "not found");
+            }
+            result => panic!("expected a response error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn mutation_input_is_sent_as_the_body() {
+        let client = Client::new(ClientOptions::default());
+        let input = serde_json::json!({ "name": "a b" });
+        let req = client
+            .mutation_request("SetName", &input, &RequestOptions::default())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(req.method(), Method::POST);
+        let body = req.body().and_then(|body| body.as_bytes()).unwrap();
+        assert_eq!(body, br#"{"name":"a b"}"#);
+        let params: Vec<_> = req.url().query_pairs().map(|(name, _)| name).collect();
+        assert!(!params.contains(&"wg_variables".into()));
+    }
+
+    #[test]
+    fn operation_url_has_no_side_effects() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let options = ClientOptions::builder()
+            .generate_request_id(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                "id".to_string()
+            })
+            .build();
+        let client = Client::new(options);
+        let input = serde_json::json!({ "name": "a b" });
+        let url = client.operation_url("Weather", &input).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        let key = client
+            .query_key("Weather", Some(&input), &RequestOptions::default())
+            .unwrap();
+        let req = client.query_request(&key).unwrap().build().unwrap();
+        assert_eq!(&url, req.url());
+    }
+
+    #[tokio::test]
+    async fn truncated_body_is_an_incomplete_response() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = async {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n{\"d")
+                .await
+                .unwrap();
+        };
+        let client = reqwest::Client::new();
+        let (resp, ()) = tokio::join!(client.get(format!("http://{}", addr)).send(), server);
+        let result = read_response(resp.unwrap(), None).await;
+        assert!(matches!(
+            result,
+            Err(Error::IncompleteResponse {
+                expected: 10,
+                received: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn clones_share_the_concurrency_limit() {
+        let client = Client::new(ClientOptions::builder().max_concurrent(1).build());
+        let clone = client.clone();
+        let permit = client.concurrency_permit().now_or_never().unwrap();
+        assert!(permit.is_some());
+        assert!(clone.concurrency_permit().now_or_never().is_none());
+        drop(permit);
+        assert!(clone.concurrency_permit().now_or_never().is_some());
+    }
+
+    #[test]
+    fn clones_share_the_bearer_token() {
+        let mut client = Client::new(ClientOptions::default());
+        let clone = client.clone();
+        client.set_bearer_token(Some("token".to_string()));
+        let req = clone.unchecked_request(Method::GET, "Weather").unwrap();
+        let req = req.build().unwrap();
+        assert_eq!(req.headers()[AUTHORIZATION], "Bearer token");
+    }
+
+    #[test]
+    fn data_is_not_an_error() {
+        let body = br#"{"data":{"errors":1}}"#;
+        let data = decode_bytes::<serde_json::Value>("Weather", StatusCode::OK, body).unwrap();
+        assert_eq!(data, serde_json::json!({ "errors": 1 }));
+    }
+}
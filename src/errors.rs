@@ -8,25 +8,186 @@ pub enum Error {
     SerializationError(#[from] serde_json::Error),
     #[error("invalid HTTP response status code {0}")]
     InvalidHTTPStatusCodeError(u16),
+    #[error("error deserializing response: {source}, body: {body}")]
+    DeserializationError {
+        source: serde_json::Error,
+        /// Status code of the response
+        status: u16,
+        /// The response body, truncated if it's too long
+        body: String,
+    },
+    #[error("request failed with status {status} and non-JSON body ({content_type}): {body}")]
+    NonJsonErrorResponse {
+        status: u16,
+        content_type: String,
+        /// The response body, truncated if it's too long
+        body: String,
+    },
+    /// The body isn't text, e.g. because it's compressed with an encoding
+    /// whose feature isn't enabled
+    #[error(
+        "response body with status {status} isn't valid UTF-8, if it's compressed enable the \
+         `gzip` or `brotli` feature"
+    )]
+    InvalidResponseEncoding { status: u16 },
+    /// The operation isn't one of [`crate::ClientOptions::operations`]
+    #[error("unknown operation {0}")]
+    UnknownOperation(String),
+    #[error("invalid operation URL: {0}")]
+    UrlError(#[from] url::ParseError),
+    #[error("failed to send request: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("received {received} bytes of a response with a length of {expected} bytes")]
+    IncompleteResponse { expected: u64, received: usize },
+    #[error("response body exceeds the limit of {limit} bytes")]
+    ResponseTooLarge { limit: usize },
+    #[error("request timed out")]
+    TimeoutError,
+    #[error("operation didn't complete before its deadline")]
+    DeadlineExceeded,
+    #[error("stream wasn't established within the handshake timeout")]
+    HandshakeTimeout,
+    #[error("no data received from the stream within the idle timeout")]
+    IdleTimeout,
+    #[error("stream ended in the middle of a frame")]
+    UnexpectedStreamEnd,
+    /// The connection of a WebSocket or server-sent event stream closed
+    /// without the server completing it
+    #[error("connection closed before the stream was completed")]
+    ConnectionClosed,
+    #[error("request was cancelled")]
+    Cancelled,
     #[error("GraphQL error")]
     ResponseError(#[from] ResponseError),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+impl Error {
+    /// Returns the underlying HTTP error if sending the request failed, e.g.
+    /// to tell connection failures apart from other errors
+    pub fn as_reqwest_error(&self) -> Option<&reqwest::Error> {
+        match self {
+            Error::RequestError(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Returns the HTTP status code of the response which caused the error,
+    /// if a response was received at all
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            Error::InvalidHTTPStatusCodeError(status)
+            | Error::DeserializationError { status, .. }
+            | Error::NonJsonErrorResponse { status, .. }
+            | Error::InvalidResponseEncoding { status } => Some(*status),
+            Error::ResponseError(error) => Some(error.status_code),
+            Error::RequestError(error) => error.status().map(|status| status.as_u16()),
+            _ => None,
+        }
+    }
+
+    /// Whether the server responded with a 4xx status code
+    pub fn is_client_error(&self) -> bool {
+        self.status_code()
+            .is_some_and(|status| (400..500).contains(&status))
+    }
+
+    /// Whether the server responded with a 5xx status code
+    pub fn is_server_error(&self) -> bool {
+        self.status_code()
+            .is_some_and(|status| (500..600).contains(&status))
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Maximum number of response body bytes included in errors
+const MAX_ERROR_BODY_LEN: usize = 1024;
+
+pub(crate) fn truncate_body(data: &[u8]) -> String {
+    if data.len() <= MAX_ERROR_BODY_LEN {
+        return String::from_utf8_lossy(data).into_owned();
+    }
+    let mut body = String::from_utf8_lossy(&data[..MAX_ERROR_BODY_LEN]).into_owned();
+    body.push_str("...");
+    body
+}
+
 /// An error response, consisting of a status code and an optional list of errors
-#[derive(Deserialize, Serialize, Debug, thiserror::Error)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, thiserror::Error)]
 pub struct ResponseError {
     pub status_code: u16,
     pub code: Option<String>,
     pub errors: Vec<GraphQLError>,
+    /// The `X-Request-Id` header of the response, to find the request in the
+    /// server's logs
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+impl ResponseError {
+    /// Returns the error code the server sent along with the errors, e.g.
+    /// `InputValidationError`
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    /// Returns the error code as a [`WunderGraphErrorCode`], or `None` if
+    /// the server didn't send one
+    pub fn known_code(&self) -> Option<WunderGraphErrorCode> {
+        self.code.as_deref().map(WunderGraphErrorCode::from)
+    }
+}
+
+/// Error codes the WunderGraph server sends along with GraphQL errors, see
+/// [`ResponseError::known_code`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WunderGraphErrorCode {
+    /// The variables don't match the operation's input schema
+    InputValidationError,
+    /// The operation requires authentication or a role the user doesn't have
+    AuthorizationError,
+    /// Too many requests were sent, try again later
+    RateLimited,
+    /// A code this client doesn't know about
+    Other(String),
+}
+
+impl WunderGraphErrorCode {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::InputValidationError => "InputValidationError",
+            Self::AuthorizationError => "AuthorizationError",
+            Self::RateLimited => "RateLimited",
+            Self::Other(code) => code,
+        }
+    }
+}
+
+impl From<&str> for WunderGraphErrorCode {
+    fn from(code: &str) -> Self {
+        match code {
+            "InputValidationError" => Self::InputValidationError,
+            "AuthorizationError" => Self::AuthorizationError,
+            "RateLimited" => Self::RateLimited,
+            code => Self::Other(code.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for WunderGraphErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 impl fmt::Display for ResponseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "status code {}", self.status_code)?;
+        if let Some(code) = &self.code {
+            write!(f, " [{}]", code)?;
+        }
         if !self.errors.is_empty() {
             write!(f, ": ")?;
         }
@@ -36,25 +197,64 @@ impl fmt::Display for ResponseError {
             }
             write!(f, "{}", error)?;
         }
+        if let Some(request_id) = &self.request_id {
+            write!(f, " (request ID {})", request_id)?;
+        }
         Ok(())
     }
 }
 
-/// A collection of GraphQL errors as returned from the server
-#[derive(Deserialize, Serialize, Debug)]
-pub struct GraphQLErrors {
-    pub code: Option<String>,
-    pub errors: Vec<GraphQLError>,
-}
-
 /// A single GraphQL error with a message
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct GraphQLError {
     pub message: String,
+    /// Path of the response field which caused the error
+    pub path: Option<Vec<serde_json::Value>>,
+    /// Locations in the operation document the error refers to
+    pub locations: Option<Vec<Location>>,
+    /// Additional metadata attached by the server, like error codes
+    pub extensions: Option<serde_json::Value>,
 }
 
 impl fmt::Display for GraphQLError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.message)
+        write!(f, "{}", self.message)?;
+        if let Some(path) = &self.path {
+            write!(f, " (path: ")?;
+            for (ii, segment) in path.iter().enumerate() {
+                if ii > 0 {
+                    write!(f, ".")?;
+                }
+                match segment {
+                    serde_json::Value::String(segment) => write!(f, "{}", segment)?,
+                    segment => write!(f, "{}", segment)?,
+                }
+            }
+            write!(f, ")")?;
+        }
+        if let Some(locations) = &self.locations {
+            write!(f, " (locations: ")?;
+            for (ii, location) in locations.iter().enumerate() {
+                if ii > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", location)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// A location in a GraphQL document
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
     }
 }
@@ -0,0 +1,251 @@
+use serde::de::IgnoredAny;
+
+use crate::{Error, Result};
+
+/// Accumulates the chunks of a streaming response and splits them into
+/// frames, one per JSON message, since chunk boundaries don't necessarily
+/// match message boundaries
+#[derive(Default)]
+pub(crate) struct FrameBuffer {
+    buf: Vec<u8>,
+    /// Whether the body uses `text/event-stream` framing instead of one
+    /// message per line
+    event_stream: bool,
+    /// Data of the event currently being received
+    event_data: Option<Vec<u8>>,
+    /// Whether the server sent a `complete` event, ending the stream
+    completed: bool,
+}
+
+impl FrameBuffer {
+    /// Returns a buffer which splits the body into server-sent events, whose
+    /// data is returned as the frames
+    pub(crate) fn event_stream() -> Self {
+        Self {
+            event_stream: true,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Returns the next complete frame, if any. Messages are read as JSON
+    /// values, so they don't need to be on a line of their own and may span
+    /// several lines. Whitespace between them is skipped, since messages
+    /// might be separated by more than one newline and the server sends
+    /// newlines to keep the connection alive
+    pub(crate) fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.event_stream {
+            return self.next_event();
+        }
+        let mut values = serde_json::Deserializer::from_slice(&self.buf).into_iter::<IgnoredAny>();
+        match values.next()? {
+            Ok(_) => {
+                let end = values.byte_offset();
+                let frame: Vec<u8> = self.buf.drain(..end).collect();
+                let start = frame.iter().position(|b| !b.is_ascii_whitespace());
+                Some(frame[start.unwrap_or_default()..].to_vec())
+            }
+            // The rest of the message hasn't been received yet
+            Err(e) if e.is_eof() => None,
+            // Not JSON, return the line so decoding it reports the error
+            Err(_) => loop {
+                let frame = self.next_line()?;
+                if !is_blank(&frame) {
+                    return Some(frame);
+                }
+            },
+        }
+    }
+
+    /// Whether the body uses `text/event-stream` framing
+    pub(crate) fn is_event_stream(&self) -> bool {
+        self.event_stream
+    }
+
+    /// Whether the server signaled that the stream is complete, so no more
+    /// frames follow
+    pub(crate) fn is_completed(&self) -> bool {
+        self.completed
+    }
+
+    /// Returns the data of the next complete event. Comments and fields other
+    /// than `data` are ignored, as are events without data. A `complete`
+    /// event marks the stream as completed, no frames are returned after it
+    fn next_event(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let mut line = self.next_line()?;
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if line.is_empty() {
+                // The data of the `complete` event, if any, isn't a frame
+                if self.completed {
+                    self.event_data = None;
+                    return None;
+                }
+                match self.event_data.take() {
+                    Some(data) => return Some(data),
+                    None => continue,
+                }
+            }
+            if line[0] == b':' {
+                continue;
+            }
+            let (field, value) = match line.iter().position(|b| *b == b':') {
+                Some(pos) => (&line[..pos], &line[pos + 1..]),
+                None => (&line[..], &[][..]),
+            };
+            let value = value.strip_prefix(b" ").unwrap_or(value);
+            if field == b"event" && value == b"complete" {
+                self.completed = true;
+            }
+            if field != b"data" {
+                continue;
+            }
+            match &mut self.event_data {
+                Some(data) => {
+                    data.push(b'\n');
+                    data.extend_from_slice(value);
+                }
+                None => self.event_data = Some(value.to_vec()),
+            }
+        }
+    }
+
+    fn next_line(&mut self) -> Option<Vec<u8>> {
+        let pos = self.buf.iter().position(|b| *b == b'\n')?;
+        let mut line: Vec<u8> = self.buf.drain(..=pos).collect();
+        line.pop();
+        Some(line)
+    }
+
+    /// Returns whatever is left in the buffer after the stream has ended. The
+    /// last message doesn't need a trailing newline, but a message cut off by
+    /// the end of the stream is an [`Error::UnexpectedStreamEnd`], as is an
+    /// event which wasn't terminated by a blank line
+    pub(crate) fn finish(self) -> Result<Option<Vec<u8>>> {
+        if self.event_stream {
+            if self.event_data.is_some() || !is_blank(&self.buf) {
+                return Err(Error::UnexpectedStreamEnd);
+            }
+            return Ok(None);
+        }
+        if is_blank(&self.buf) {
+            return Ok(None);
+        }
+        match serde_json::from_slice::<IgnoredAny>(&self.buf) {
+            Err(e) if e.is_eof() => Err(Error::UnexpectedStreamEnd),
+            _ => Ok(Some(self.buf)),
+        }
+    }
+}
+
+fn is_blank(frame: &[u8]) -> bool {
+    frame.iter().all(u8::is_ascii_whitespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(buffer: &mut FrameBuffer) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| buffer.next_frame()).collect()
+    }
+
+    #[test]
+    fn message_split_over_two_chunks() {
+        let mut buffer = FrameBuffer::default();
+        buffer.push(br#"{"data":{"#);
+        assert!(frames(&mut buffer).is_empty());
+        buffer.push(b"\"n\":1}}\n");
+        assert_eq!(frames(&mut buffer), vec![br#"{"data":{"n":1}}"#.to_vec()]);
+    }
+
+    #[test]
+    fn keep_alive_newline_between_frames() {
+        let mut buffer = FrameBuffer::default();
+        buffer.push(b"{\"data\":{\"n\":1}}\n\n");
+        buffer.push(b"\n");
+        buffer.push(b"{\"data\":{\"n\":2}}\n\n");
+        assert_eq!(
+            frames(&mut buffer),
+            vec![
+                br#"{"data":{"n":1}}"#.to_vec(),
+                br#"{"data":{"n":2}}"#.to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn multiple_messages_in_one_chunk() {
+        let mut buffer = FrameBuffer::default();
+        buffer.push(b"{\"data\":{\"n\":1}}{\"data\":{\"n\":2}}\r\n{\"data\":{\"n\":3}}");
+        assert_eq!(
+            frames(&mut buffer),
+            vec![
+                br#"{"data":{"n":1}}"#.to_vec(),
+                br#"{"data":{"n":2}}"#.to_vec(),
+                br#"{"data":{"n":3}}"#.to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn message_split_over_three_chunks() {
+        let mut buffer = FrameBuffer::default();
+        buffer.push(br#"{"data":"#);
+        assert!(frames(&mut buffer).is_empty());
+        buffer.push(b"\n  {\"n\"");
+        assert!(frames(&mut buffer).is_empty());
+        buffer.push(b": 3}}");
+        assert_eq!(
+            frames(&mut buffer),
+            vec![b"{\"data\":\n  {\"n\": 3}}".to_vec()]
+        );
+    }
+
+    #[test]
+    fn invalid_line_is_returned_as_a_frame() {
+        let mut buffer = FrameBuffer::default();
+        buffer.push(b"not json\n{\"data\":{\"n\":1}}\n");
+        assert_eq!(
+            frames(&mut buffer),
+            vec![b"not json".to_vec(), br#"{"data":{"n":1}}"#.to_vec()]
+        );
+    }
+
+    #[test]
+    fn truncated_message_at_the_end_is_an_error() {
+        let mut buffer = FrameBuffer::default();
+        buffer.push(b"{\"data\":{\"n\":1}}\n{\"data\":{");
+        assert_eq!(frames(&mut buffer).len(), 1);
+        assert!(matches!(buffer.finish(), Err(Error::UnexpectedStreamEnd)));
+    }
+
+    #[test]
+    fn last_message_without_newline_is_returned() {
+        let mut buffer = FrameBuffer::default();
+        buffer.push(b"not json");
+        assert!(frames(&mut buffer).is_empty());
+        assert_eq!(buffer.finish().unwrap(), Some(b"not json".to_vec()));
+    }
+
+    #[test]
+    fn unterminated_event_is_an_error() {
+        let mut buffer = FrameBuffer::event_stream();
+        buffer.push(b"data: {\"data\":{\"n\":1}}\n\ndata: {\"data\":{\"n\":2}}\n");
+        assert_eq!(frames(&mut buffer), vec![br#"{"data":{"n":1}}"#.to_vec()]);
+        assert!(matches!(buffer.finish(), Err(Error::UnexpectedStreamEnd)));
+    }
+
+    #[test]
+    fn terminated_event_stream_ends_cleanly() {
+        let mut buffer = FrameBuffer::event_stream();
+        buffer.push(b"data: {\"data\":{\"n\":1}}\n\n: keep-alive\n\n");
+        assert_eq!(frames(&mut buffer).len(), 1);
+        assert!(matches!(buffer.finish(), Ok(None)));
+    }
+}
@@ -0,0 +1,178 @@
+use reqwest::{
+    header::{HeaderMap, CONTENT_TYPE},
+    StatusCode,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{errors::truncate_body, Error, GraphQLError, Result};
+
+/// A payload of a query using incremental delivery, see
+/// [`crate::Client::query_incremental`]
+#[derive(Debug, PartialEq)]
+pub struct IncrementalPayload {
+    /// For the initial payload, the data resolved so far. For subsequent
+    /// ones, the deferred fragment or the streamed list items, as an array
+    pub data: Option<Value>,
+    /// Path of the field the data belongs to, empty for the initial payload
+    pub path: Vec<Value>,
+    pub errors: Vec<GraphQLError>,
+    /// Whether more payloads follow
+    pub has_next: bool,
+}
+
+/// A single part of the response, in either the original format of the
+/// incremental delivery spec or the newer one grouping the payloads in
+/// `incremental`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Part {
+    #[serde(flatten)]
+    payload: Payload,
+    incremental: Option<Vec<Payload>>,
+    #[serde(default)]
+    has_next: bool,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    data: Option<Value>,
+    items: Option<Vec<Value>>,
+    #[serde(default)]
+    path: Vec<Value>,
+    #[serde(default)]
+    errors: Vec<GraphQLError>,
+}
+
+impl Payload {
+    fn is_empty(&self) -> bool {
+        self.data.is_none() && self.items.is_none() && self.errors.is_empty()
+    }
+
+    fn into_payload(self, has_next: bool) -> IncrementalPayload {
+        IncrementalPayload {
+            data: self.data.or(self.items.map(Value::Array)),
+            path: self.path,
+            errors: self.errors,
+            has_next,
+        }
+    }
+}
+
+/// Decodes a part into its payloads. Parts without any, like the empty
+/// objects some servers send to keep the connection alive, yield none
+pub(crate) fn decode_part(status: StatusCode, data: &[u8]) -> Result<Vec<IncrementalPayload>> {
+    let part: Part =
+        serde_json::from_slice(data).map_err(|source| Error::DeserializationError {
+            source,
+            status: status.as_u16(),
+            body: truncate_body(data),
+        })?;
+    let mut payloads = Vec::new();
+    if !part.payload.is_empty() {
+        payloads.push(part.payload);
+    }
+    payloads.extend(part.incremental.unwrap_or_default());
+    let count = payloads.len();
+    let payloads = payloads
+        .into_iter()
+        .enumerate()
+        .map(|(ii, payload)| payload.into_payload(ii + 1 < count || part.has_next))
+        .collect();
+    Ok(payloads)
+}
+
+/// Returns the boundary of a `multipart/mixed` response, or `None` if the
+/// response has another content type
+pub(crate) fn multipart_boundary(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let mut params = content_type.split(';');
+    let essence = params.next()?.trim();
+    if !essence.eq_ignore_ascii_case("multipart/mixed") {
+        return None;
+    }
+    let boundary = params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+    });
+    // The spec makes "-" the default boundary
+    Some(boundary.unwrap_or_else(|| "-".to_string()))
+}
+
+/// Accumulates the chunks of a `multipart/mixed` response and splits them
+/// into the bodies of its parts
+pub(crate) struct MultipartBuffer {
+    /// The boundary, preceded by a line break, since it might appear in the
+    /// bodies otherwise
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    /// Whether the preamble before the first delimiter has been skipped
+    started: bool,
+    /// Whether the closing delimiter has been received
+    done: bool,
+}
+
+impl MultipartBuffer {
+    pub(crate) fn new(boundary: &str) -> Self {
+        Self {
+            delimiter: format!("\n--{}", boundary).into_bytes(),
+            // The first delimiter might start the body without a line break
+            buf: b"\n".to_vec(),
+            started: false,
+            done: false,
+        }
+    }
+
+    pub(crate) fn push(&mut self, chunk: &[u8]) {
+        if !self.done {
+            self.buf.extend_from_slice(chunk);
+        }
+    }
+
+    /// Whether the closing delimiter has been received, so no more parts
+    /// follow
+    pub(crate) fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Returns the body of the next complete part, if any. Part headers are
+    /// dropped
+    pub(crate) fn next_part(&mut self) -> Option<Vec<u8>> {
+        while !self.done {
+            let pos = find(&self.buf, &self.delimiter)?;
+            let part: Vec<u8> = self
+                .buf
+                .drain(..pos + self.delimiter.len())
+                .take(pos)
+                .collect();
+            if self.buf.starts_with(b"--") {
+                self.done = true;
+                self.buf.clear();
+            }
+            if !std::mem::replace(&mut self.started, true) {
+                continue;
+            }
+            let body = match find(&part, b"\r\n\r\n") {
+                Some(end) => &part[end + 4..],
+                None => match find(&part, b"\n\n") {
+                    Some(end) => &part[end + 2..],
+                    None => continue,
+                },
+            };
+            // The line break before the delimiter belongs to it
+            let body = body.strip_suffix(b"\r").unwrap_or(body);
+            if !body.iter().all(u8::is_ascii_whitespace) {
+                return Some(body.to_vec());
+            }
+        }
+        None
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,17 @@
+use futures_util::future::BoxFuture;
+use reqwest::RequestBuilder;
+
+/// Hooks into every request sent by the client, e.g. for logging, metrics or
+/// refreshing credentials. Interceptors run in the order they were added
+pub trait Interceptor: Send + Sync {
+    /// Called before a request is sent. The returned builder is sent instead
+    fn on_request(&self, request: RequestBuilder) -> BoxFuture<'_, RequestBuilder> {
+        Box::pin(async move { request })
+    }
+
+    /// Called once the response headers have been received, before the body
+    /// is read. Retried requests call it for every response
+    fn on_response<'a>(&'a self, _response: &'a reqwest::Response) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
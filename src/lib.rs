@@ -1,7 +1,74 @@
+//! Client for WunderGraph applications
+//!
+//! # Features
+//!
+//! - `blocking`: provides `BlockingClient`, for code that isn't async
+//! - `cancellation`: allows cancelling all requests of a client at once, see
+//!   `ClientOptions::shutdown`
+//! - `cookies`: allows storing cookies set by the server and sending them
+//!   with subsequent requests, see `ClientOptions::cookie_store`
+//! - `gzip`, `brotli`: decompress responses using the given encodings. The
+//!   client advertises them in the `Accept-Encoding` header of every request
+//! - `json-patch`: allows receiving live query updates as JSON patches, see
+//!   `Client::live_query_patched`
+//! - `multipart`: allows uploading files with mutations, see
+//!   `Client::upload`
+//! - `tls`: enables TLS through reqwest's native TLS backend, and the TLS
+//!   settings in [`ClientOptions`]
+//! - `websocket`: allows sending subscriptions over WebSocket connections,
+//!   see [`SubscriptionTransport`], and sharing a connection between many
+//!   subscriptions, see `Client::subscription_manager`
+//!
+//! Decompression only applies to the HTTP client created by [`Client`]
+//! itself. When passing a client through [`ClientOptions::http_client`],
+//! configure it on that client instead.
+
+#[cfg(feature = "blocking")]
+mod blocking;
+mod cache;
 mod client;
 mod errors;
+mod frames;
+mod incremental;
+mod interceptor;
+mod metrics;
+mod operation;
+mod options;
+#[cfg(feature = "json-patch")]
+mod patch;
+mod rate_limit;
+mod retry;
+mod stream_ext;
+mod stream_item;
+#[cfg(feature = "websocket")]
+mod websocket;
 
-pub use client::{Client, ClientOptions, Stream, StreamExt};
-pub use errors::{Error, GraphQLError, ResponseError, Result};
+#[cfg(feature = "blocking")]
+pub use blocking::BlockingClient;
+pub use client::{BatchRequest, Client, OperationKey, PartialResponse, Stream, StreamExt};
+pub use errors::{Error, GraphQLError, Location, ResponseError, Result, WunderGraphErrorCode};
+pub use incremental::IncrementalPayload;
+pub use interceptor::Interceptor;
+pub use metrics::{MetricEvent, MetricHook, MetricOutcome, OperationType};
+pub use operation::{Operation, OperationKind, SubscriptionOperation};
+pub use options::{
+    ClientOptions, ClientOptionsBuilder, HeaderProvider, QueryParamNames, RedirectPolicy,
+    RequestIdGenerator, RequestOptions, RequestSigner, SubscriptionTransport, UnauthorizedHook,
+};
+#[cfg(feature = "json-patch")]
+pub use patch::LiveQueryUpdate;
+pub use rate_limit::RateLimit;
+pub use retry::{ReconnectPolicy, RetryPolicy};
+pub use stream_ext::ResultStreamExt;
+pub use stream_item::StreamItem;
+#[cfg(feature = "websocket")]
+pub use websocket::SubscriptionManager;
 
-pub use reqwest::Url;
+pub use futures_util::future::AbortHandle;
+#[cfg(feature = "json-patch")]
+pub use json_patch;
+#[cfg(feature = "multipart")]
+pub use reqwest::multipart;
+pub use reqwest::{Method, StatusCode, Url};
+#[cfg(feature = "cancellation")]
+pub use tokio_util::sync::CancellationToken;
@@ -0,0 +1,118 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{Error, Result, StreamItem};
+
+/// Called with a [`MetricEvent`] for every finished operation
+pub type MetricHook = Arc<dyn Fn(MetricEvent) + Send + Sync>;
+
+/// Describes a finished operation. Queries and mutations report once they
+/// complete. Subscriptions and live queries report once their stream ends or
+/// is dropped, or when their connection can't be established
+#[derive(Debug, Clone)]
+pub struct MetricEvent {
+    pub subpath: String,
+    pub operation_type: OperationType,
+    /// Time from sending the operation until it finished
+    pub duration: Duration,
+    /// For streams, the outcome of the last item received
+    pub outcome: MetricOutcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationType {
+    Query,
+    Mutation,
+    Subscription,
+    LiveQuery,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricOutcome {
+    Success,
+    /// The server responded with GraphQL errors
+    GraphQLError,
+    /// Any other failure, e.g. the request couldn't be sent, the server
+    /// responded with an error status or the response couldn't be decoded
+    HttpError,
+}
+
+impl MetricOutcome {
+    fn of<T>(result: &Result<T>) -> Self {
+        match result {
+            Ok(_) => Self::Success,
+            Err(error) => Self::of_error(error),
+        }
+    }
+
+    fn of_error(error: &Error) -> Self {
+        match error {
+            Error::ResponseError(_) => Self::GraphQLError,
+            _ => Self::HttpError,
+        }
+    }
+}
+
+/// Measures a single operation and reports it to the hook when dropped.
+/// Operations which are cancelled before producing any result aren't reported
+pub(crate) struct OperationMetrics {
+    hook: Option<MetricHook>,
+    subpath: String,
+    operation_type: OperationType,
+    start: Instant,
+    outcome: Option<MetricOutcome>,
+}
+
+impl OperationMetrics {
+    pub(crate) fn new(
+        hook: Option<MetricHook>,
+        subpath: &str,
+        operation_type: OperationType,
+    ) -> Self {
+        Self {
+            // Nothing is reported without a hook, so don't copy the subpath
+            subpath: hook
+                .as_ref()
+                .map(|_| subpath.to_string())
+                .unwrap_or_default(),
+            hook,
+            operation_type,
+            start: Instant::now(),
+            outcome: None,
+        }
+    }
+
+    pub(crate) fn observe<T>(&mut self, result: &Result<T>) {
+        self.outcome = Some(MetricOutcome::of(result));
+    }
+
+    /// Like [`OperationMetrics::observe`], for streams. The end of a stream
+    /// only changes the outcome if an error ended it
+    pub(crate) fn observe_item<R>(&mut self, item: &StreamItem<R>) {
+        let outcome = match item {
+            StreamItem::Data(_) => MetricOutcome::Success,
+            StreamItem::GraphQLError(_) => MetricOutcome::GraphQLError,
+            StreamItem::InvalidFrame(error) | StreamItem::StreamEnded(Some(error)) => {
+                MetricOutcome::of_error(error)
+            }
+            StreamItem::StreamEnded(None) => return,
+        };
+        self.outcome = Some(outcome);
+    }
+}
+
+impl Drop for OperationMetrics {
+    fn drop(&mut self) {
+        let (Some(hook), Some(outcome)) = (&self.hook, self.outcome) else {
+            return;
+        };
+        hook(MetricEvent {
+            subpath: std::mem::take(&mut self.subpath),
+            operation_type: self.operation_type,
+            duration: self.start.elapsed(),
+            outcome,
+        });
+    }
+}
@@ -0,0 +1,58 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+/// How an [`Operation`] is sent to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    Query,
+    Mutation,
+}
+
+/// A query or mutation with its input and output types, so call sites don't
+/// have to repeat them. Usually implemented by generated code
+pub trait Operation {
+    type Input: Serialize;
+    type Output: DeserializeOwned;
+
+    /// Subpath of the operation, e.g. `"Weather"`
+    const PATH: &'static str;
+    const KIND: OperationKind = OperationKind::Query;
+}
+
+/// Like [`Operation`], but for subscriptions
+pub trait SubscriptionOperation {
+    type Input: Serialize;
+    type Output: DeserializeOwned;
+
+    /// Subpath of the operation, e.g. `"Countdown"`
+    const PATH: &'static str;
+}
+
+/// Declares a module with a constant for each operation of the application,
+/// so a misspelled operation name fails to compile instead of failing with a
+/// 404 at runtime. `ALL` lists every subpath, pass it to
+/// [`crate::ClientOptionsBuilder::operations`] to reject operations missing
+/// from the list before sending them.
+///
+/// `wg_operations!(pub mod ops { Weather, Countries })` declares
+/// `ops::Weather` and `ops::Countries`. Operations in subdirectories need
+/// their subpath spelled out, as in `GetUser = "users/Get"`
+#[macro_export]
+macro_rules! wg_operations {
+    ($vis:vis mod $module:ident { $($operation:ident $(= $path:literal)?),* $(,)? }) => {
+        $vis mod $module {
+            $(
+                #[allow(non_upper_case_globals)]
+                pub const $operation: &str = $crate::wg_operations!(@path $operation $($path)?);
+            )*
+
+            /// Subpaths of all operations
+            pub const ALL: &[&str] = &[$($operation),*];
+        }
+    };
+    (@path $operation:ident) => {
+        stringify!($operation)
+    };
+    (@path $operation:ident $path:literal) => {
+        $path
+    };
+}
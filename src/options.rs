@@ -0,0 +1,585 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures_util::future::{BoxFuture, FutureExt};
+
+use reqwest::{header::HeaderMap, Url};
+
+use crate::{Interceptor, MetricEvent, MetricHook, RateLimit, ReconnectPolicy, RetryPolicy};
+
+#[derive(Default, Clone)]
+pub struct ClientOptions {
+    /// Base URL of the WunderGraph server. Defaults to
+    /// `http://localhost:9991/` unless `require_url` is set
+    pub url: Option<Url>,
+    /// Fail to create the client when `url` isn't set, instead of falling
+    /// back to localhost
+    pub require_url: bool,
+    pub application_hash: Option<String>,
+    /// Path the operations are served under, relative to `url`. Defaults to
+    /// `/operations/`
+    pub operations_path: Option<String>,
+    /// Subpaths of the operations the application provides, usually the
+    /// `ALL` constant declared by [`crate::wg_operations`]. If set, other
+    /// operations fail with [`crate::Error::UnknownOperation`] without being
+    /// sent
+    pub operations: Option<&'static [&'static str]>,
+    /// HTTP client used to send requests. If `None`, a new one is created
+    /// using the connection settings below, which are ignored otherwise
+    pub http_client: Option<reqwest::Client>,
+    /// `User-Agent` header sent with every request. Defaults to
+    /// `wundergraph-rust-client/<version>`
+    pub user_agent: Option<String>,
+    /// Use HTTP/2 without negotiating it first
+    pub http2_prior_knowledge: bool,
+    /// Maximum number of idle connections kept per host
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long idle connections are kept before being closed
+    pub pool_idle_timeout: Option<Duration>,
+    /// Maximum time to establish a connection, including subscriptions and
+    /// live queries, independently of `timeout`
+    pub connect_timeout: Option<Duration>,
+    /// Proxy all requests are sent through
+    pub proxy: Option<Url>,
+    /// Username and password sent to `proxy` using basic authentication
+    pub proxy_auth: Option<(String, String)>,
+    /// How redirects are followed. Following redirects sends the request to
+    /// wherever the server points it, including any headers set through
+    /// `headers` or `header_provider`. reqwest drops `Authorization` when
+    /// redirected to another host, but other credentials, like API keys, are
+    /// sent along, so consider disabling redirects if the server shouldn't
+    /// send any
+    pub redirect_policy: RedirectPolicy,
+    /// Store cookies set by the server and send them with subsequent
+    /// requests
+    #[cfg(feature = "cookies")]
+    pub cookie_store: bool,
+    /// Cookie jar to use instead of a new one, e.g. to share cookies with
+    /// other clients or to set cookies up front. Implies `cookie_store`
+    #[cfg(feature = "cookies")]
+    pub cookie_jar: Option<Arc<reqwest::cookie::Jar>>,
+    /// Certificates trusted in addition to the system's root certificates
+    #[cfg(feature = "tls")]
+    pub root_certificates: Vec<reqwest::Certificate>,
+    /// Client certificate presented to the server, for mutual TLS
+    #[cfg(feature = "tls")]
+    pub identity: Option<reqwest::Identity>,
+    /// Accept any server certificate, even invalid or expired ones.
+    ///
+    /// This is unsafe: it makes the connection vulnerable to
+    /// man-in-the-middle attacks. Only use it in development environments
+    #[cfg(feature = "tls")]
+    pub danger_accept_invalid_certs: bool,
+    /// Timeout for queries and mutations. For subscriptions and live queries
+    /// it only applies to receiving the response headers, not to the stream
+    pub timeout: Option<Duration>,
+    /// Maximum size of query and mutation response bodies. Larger responses
+    /// fail with [`crate::Error::ResponseTooLarge`] without being read
+    /// completely
+    pub max_response_bytes: Option<usize>,
+    /// Fail subscriptions and live queries with
+    /// [`crate::Error::HandshakeTimeout`] if their connection isn't
+    /// established within this time, instead of applying `timeout`
+    pub handshake_timeout: Option<Duration>,
+    /// Log the status and body of every response, and every frame of streams,
+    /// at trace level. Bodies are truncated, but might still contain
+    /// sensitive data, so only enable this for debugging
+    pub log_response_bodies: bool,
+    /// End subscriptions and live queries with [`crate::Error::IdleTimeout`]
+    /// if nothing, not even a keep-alive, is received for this long. Stalled
+    /// streams are reconnected like failed ones if `reconnect` is set
+    pub idle_timeout: Option<Duration>,
+    /// Log and skip frames of subscriptions and live queries which can't be
+    /// decoded, instead of yielding them as errors, so consumers which stop
+    /// at the first error keep receiving updates
+    pub skip_undecodable_frames: bool,
+    /// Token sent as `Authorization: Bearer <token>` with every request
+    pub bearer_token: Option<String>,
+    /// `Accept` header sent with queries and mutations. Defaults to
+    /// `application/json`, set it to `application/graphql-response+json` to
+    /// have the server use HTTP status codes for request errors as described
+    /// by the GraphQL over HTTP spec. Responses are decoded the same way for
+    /// both, GraphQL errors in a non-2xx response are returned as
+    /// [`crate::Error::ResponseError`] along with the status code
+    pub accept: Option<String>,
+    /// Headers sent with every request. They replace the client's defaults,
+    /// i.e. `Accept`, `Content-Type` and the `Authorization` header set from
+    /// `bearer_token`, but are themselves replaced by the headers from
+    /// `header_provider`, then `X-Request-Id` and the signature header, and
+    /// finally the `Accept` header of server-sent event and incremental
+    /// delivery streams
+    pub headers: HeaderMap,
+    /// Called when a query or mutation fails with `401 Unauthorized`, to get
+    /// a new bearer token. The request is then sent once more with the new
+    /// token, which is also used by all subsequent requests
+    pub on_unauthorized: Option<UnauthorizedHook>,
+    /// Called for every request to get additional headers, e.g. to propagate
+    /// tracing context or to send tokens which change over time. These are
+    /// applied after `headers` and replace them
+    pub header_provider: Option<HeaderProvider>,
+    /// Called for every request to get an ID to send as `X-Request-Id`, e.g.
+    /// a UUID, to correlate the request with the server's logs. The ID is
+    /// also recorded in the operation's span
+    pub generate_request_id: Option<RequestIdGenerator>,
+    /// Called with the serialized variables of every query and mutation to
+    /// compute a signature, e.g. an HMAC, sent in `signature_header`. For
+    /// queries without variables, it's called with an empty slice. File
+    /// uploads aren't signed
+    pub signer: Option<RequestSigner>,
+    /// Header the signature is sent in. Defaults to `X-Signature`
+    pub signature_header: Option<String>,
+    /// Hooks run for every request and response
+    pub interceptors: Vec<Arc<dyn Interceptor>>,
+    /// Called once for every finished operation, e.g. to record request
+    /// counts and latencies
+    pub on_metric: Option<MetricHook>,
+    /// Reconnect subscriptions and live queries when their connection ends
+    /// or fails. If `None`, the stream ends instead
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Share a single request between identical queries (same subpath and
+    /// variables) sent while one of them is still in flight. Mutations and
+    /// streams are never deduplicated
+    pub dedupe: bool,
+    /// Keep the response of up to this many queries which were sent with an
+    /// `ETag`. Repeating one of them sends `If-None-Match`, and the cached
+    /// response is returned if the server answers with `304 Not Modified`.
+    /// Disabled if `None`
+    pub etag_cache_size: Option<usize>,
+    /// Retries failed queries. Mutations are only retried if the policy
+    /// explicitly allows it
+    pub retry: RetryPolicy,
+    /// Limit the rate of requests sent by the client and its clones. Requests
+    /// over the limit wait until they may be sent. Every attempt of a retried
+    /// query counts, as does every connection of a stream, but not its
+    /// messages
+    pub rate_limit: Option<RateLimit>,
+    /// Maximum number of operations in flight at once, across the client and
+    /// its clones. Further operations wait until one finishes. Subscriptions
+    /// and live queries count until their stream is dropped
+    pub max_concurrent: Option<usize>,
+    /// Queries whose serialized variables are longer than this many bytes
+    /// are sent as a POST request with the variables as the body, instead of
+    /// in the URL. Avoids exceeding the server's URL length limit
+    pub post_variables_threshold: Option<usize>,
+    /// Send all queries as POST requests with the variables as the body, e.g.
+    /// to keep proxies which cache GET requests from returning stale data.
+    /// The server or gateway has to accept queries sent that way, the
+    /// request doesn't mark them as queries in any other way.
+    /// [`crate::Client::query_with_method`] still uses the given method
+    pub force_post_queries: bool,
+    /// Pretty print the variables sent with queries, subscriptions and live
+    /// queries, to make them easier to read in server logs. Makes requests
+    /// larger, so only enable this for debugging
+    pub pretty_variables: bool,
+    /// Names of the query parameters used to pass data to the server
+    pub param_names: QueryParamNames,
+    /// How subscriptions are sent to the server
+    pub subscription_transport: SubscriptionTransport,
+    /// URL of the WebSocket endpoint. Defaults to `/graphql` on the host of
+    /// `url`, using the `ws` or `wss` scheme
+    #[cfg(feature = "websocket")]
+    pub websocket_url: Option<Url>,
+    /// Once cancelled, pending queries and mutations fail with
+    /// [`crate::Error::Cancelled`] and open subscriptions and live queries end
+    #[cfg(feature = "cancellation")]
+    pub shutdown: Option<tokio_util::sync::CancellationToken>,
+}
+
+/// Settings for a single operation, on top of the ones set in
+/// [`ClientOptions`]
+#[derive(Debug, Default, Clone)]
+pub struct RequestOptions {
+    /// Fail with [`crate::Error::DeadlineExceeded`] if the operation, including
+    /// any retries, hasn't completed by then. For subscriptions and live
+    /// queries, only establishing the connection is covered
+    pub deadline: Option<Instant>,
+    /// Query parameters sent in addition to the ones set by the client
+    pub query_params: Vec<(String, String)>,
+    /// Hash of the compiled operation to run, so a specific version of it is
+    /// used. Unlike the application hash, it only applies to this operation
+    pub operation_hash: Option<String>,
+    /// Method to send a query with, see [`crate::Client::query_with_method`].
+    /// Ignored for other operations
+    pub method: Option<reqwest::Method>,
+    /// Receive a subscription or live query as server-sent events, by
+    /// sending `wg_sse=true`. The response is parsed as an event stream
+    /// whatever its content type. Subscriptions sent over WebSockets and
+    /// other operations ignore it
+    pub sse: bool,
+}
+
+/// Transport used by [`crate::Client::subscribe`]. Live queries are always
+/// sent over HTTP
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SubscriptionTransport {
+    /// A streaming HTTP GET request
+    #[default]
+    Http,
+    /// A streaming HTTP GET request asking for server-sent events, like
+    /// [`RequestOptions::sse`]. Responses with the `text/event-stream`
+    /// content type are parsed as server-sent events regardless of the
+    /// transport
+    ServerSentEvents,
+    /// A WebSocket connection using the `graphql-transport-ws` protocol
+    #[cfg(feature = "websocket")]
+    WebSocket,
+}
+
+/// Whether the HTTP client follows redirects, see
+/// [`ClientOptions::redirect_policy`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RedirectPolicy {
+    /// Follow up to 10 redirects, like reqwest does by default
+    #[default]
+    Follow,
+    /// Follow up to the given number of redirects
+    Limited(usize),
+    /// Return redirect responses instead of following them
+    None,
+}
+
+impl From<RedirectPolicy> for reqwest::redirect::Policy {
+    fn from(policy: RedirectPolicy) -> Self {
+        match policy {
+            RedirectPolicy::Follow => Self::default(),
+            RedirectPolicy::Limited(max) => Self::limited(max),
+            RedirectPolicy::None => Self::none(),
+        }
+    }
+}
+
+/// Names of the query parameters the client sends. Only needs to be changed
+/// if a proxy in front of the server rewrites them
+#[derive(Debug, Clone)]
+pub struct QueryParamNames {
+    pub variables: String,
+    pub app_hash: String,
+    pub live: String,
+    pub json_patch: String,
+    pub operation_hash: String,
+    /// Asks for server-sent events, see [`RequestOptions::sse`]
+    pub sse: String,
+}
+
+impl Default for QueryParamNames {
+    fn default() -> Self {
+        Self {
+            variables: "wg_variables".to_string(),
+            app_hash: "wg_app_hash".to_string(),
+            live: "wg_live".to_string(),
+            json_patch: "wg_json_patch".to_string(),
+            operation_hash: "wg_operation_hash".to_string(),
+            sse: "wg_sse".to_string(),
+        }
+    }
+}
+
+pub type HeaderProvider = Arc<dyn Fn() -> HeaderMap + Send + Sync>;
+
+pub type RequestIdGenerator = Arc<dyn Fn() -> String + Send + Sync>;
+
+pub type RequestSigner = Arc<dyn Fn(&[u8]) -> String + Send + Sync>;
+
+pub type UnauthorizedHook =
+    Arc<dyn Fn() -> BoxFuture<'static, crate::Result<String>> + Send + Sync>;
+
+impl ClientOptions {
+    pub fn builder() -> ClientOptionsBuilder {
+        ClientOptionsBuilder::default()
+    }
+}
+
+/// Creates options pointing at the server with the given URL
+impl TryFrom<&str> for ClientOptions {
+    type Error = crate::Error;
+
+    fn try_from(url: &str) -> crate::Result<Self> {
+        Ok(Self {
+            url: Some(Url::parse(url)?),
+            ..Default::default()
+        })
+    }
+}
+
+/// Builds [`ClientOptions`] without having to spell out every field.
+/// See the fields of [`ClientOptions`] for what each setting does
+#[derive(Default, Clone)]
+pub struct ClientOptionsBuilder {
+    options: ClientOptions,
+}
+
+impl ClientOptionsBuilder {
+    pub fn url(mut self, url: Url) -> Self {
+        self.options.url = Some(url);
+        self
+    }
+
+    pub fn require_url(mut self, require_url: bool) -> Self {
+        self.options.require_url = require_url;
+        self
+    }
+
+    pub fn application_hash(mut self, application_hash: impl Into<String>) -> Self {
+        self.options.application_hash = Some(application_hash.into());
+        self
+    }
+
+    pub fn operations_path(mut self, operations_path: impl Into<String>) -> Self {
+        self.options.operations_path = Some(operations_path.into());
+        self
+    }
+
+    pub fn operations(mut self, operations: &'static [&'static str]) -> Self {
+        self.options.operations = Some(operations);
+        self
+    }
+
+    pub fn http_client(mut self, http_client: reqwest::Client) -> Self {
+        self.options.http_client = Some(http_client);
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.options.user_agent = Some(user_agent.into());
+        self
+    }
+
+    pub fn http2_prior_knowledge(mut self, http2_prior_knowledge: bool) -> Self {
+        self.options.http2_prior_knowledge = http2_prior_knowledge;
+        self
+    }
+
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.options.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.options.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Url) -> Self {
+        self.options.proxy = Some(proxy);
+        self
+    }
+
+    pub fn proxy_auth(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.options.proxy_auth = Some((username.into(), password.into()));
+        self
+    }
+
+    pub fn redirect_policy(mut self, redirect_policy: RedirectPolicy) -> Self {
+        self.options.redirect_policy = redirect_policy;
+        self
+    }
+
+    #[cfg(feature = "cookies")]
+    pub fn cookie_store(mut self, cookie_store: bool) -> Self {
+        self.options.cookie_store = cookie_store;
+        self
+    }
+
+    #[cfg(feature = "cookies")]
+    pub fn cookie_jar(mut self, cookie_jar: Arc<reqwest::cookie::Jar>) -> Self {
+        self.options.cookie_jar = Some(cookie_jar);
+        self
+    }
+
+    /// Trusts the certificate in addition to the ones added before it
+    #[cfg(feature = "tls")]
+    pub fn root_certificate(mut self, certificate: reqwest::Certificate) -> Self {
+        self.options.root_certificates.push(certificate);
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn identity(mut self, identity: reqwest::Identity) -> Self {
+        self.options.identity = Some(identity);
+        self
+    }
+
+    /// See [`ClientOptions::danger_accept_invalid_certs`] for why this is
+    /// dangerous
+    #[cfg(feature = "tls")]
+    pub fn danger_accept_invalid_certs(mut self, accept_invalid_certs: bool) -> Self {
+        self.options.danger_accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    pub fn max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.options.max_response_bytes = Some(max_response_bytes);
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.options.handshake_timeout = Some(timeout);
+        self
+    }
+
+    pub fn log_response_bodies(mut self, log_response_bodies: bool) -> Self {
+        self.options.log_response_bodies = log_response_bodies;
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.options.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    pub fn skip_undecodable_frames(mut self, skip_undecodable_frames: bool) -> Self {
+        self.options.skip_undecodable_frames = skip_undecodable_frames;
+        self
+    }
+
+    pub fn bearer_token(mut self, bearer_token: impl Into<String>) -> Self {
+        self.options.bearer_token = Some(bearer_token.into());
+        self
+    }
+
+    pub fn accept(mut self, accept: impl Into<String>) -> Self {
+        self.options.accept = Some(accept.into());
+        self
+    }
+
+    pub fn headers(mut self, headers: HeaderMap) -> Self {
+        self.options.headers = headers;
+        self
+    }
+
+    pub fn on_unauthorized<F, Fut>(mut self, on_unauthorized: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = crate::Result<String>> + Send + 'static,
+    {
+        self.options.on_unauthorized = Some(Arc::new(move || on_unauthorized().boxed()));
+        self
+    }
+
+    pub fn header_provider<F>(mut self, header_provider: F) -> Self
+    where
+        F: Fn() -> HeaderMap + Send + Sync + 'static,
+    {
+        self.options.header_provider = Some(Arc::new(header_provider));
+        self
+    }
+
+    pub fn generate_request_id<F>(mut self, generate_request_id: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.options.generate_request_id = Some(Arc::new(generate_request_id));
+        self
+    }
+
+    pub fn signer<F>(mut self, signer: F) -> Self
+    where
+        F: Fn(&[u8]) -> String + Send + Sync + 'static,
+    {
+        self.options.signer = Some(Arc::new(signer));
+        self
+    }
+
+    pub fn signature_header(mut self, signature_header: impl Into<String>) -> Self {
+        self.options.signature_header = Some(signature_header.into());
+        self
+    }
+
+    /// Adds an interceptor, which runs after the ones added before it
+    pub fn interceptor(mut self, interceptor: impl Interceptor + 'static) -> Self {
+        self.options.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    pub fn on_metric<F>(mut self, on_metric: F) -> Self
+    where
+        F: Fn(MetricEvent) + Send + Sync + 'static,
+    {
+        self.options.on_metric = Some(Arc::new(on_metric));
+        self
+    }
+
+    pub fn reconnect(mut self, reconnect: ReconnectPolicy) -> Self {
+        self.options.reconnect = Some(reconnect);
+        self
+    }
+
+    pub fn dedupe(mut self, dedupe: bool) -> Self {
+        self.options.dedupe = dedupe;
+        self
+    }
+
+    pub fn etag_cache_size(mut self, max_entries: usize) -> Self {
+        self.options.etag_cache_size = Some(max_entries);
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.options.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.options.max_concurrent = Some(max_concurrent);
+        self
+    }
+
+    pub fn post_variables_threshold(mut self, threshold: usize) -> Self {
+        self.options.post_variables_threshold = Some(threshold);
+        self
+    }
+
+    pub fn force_post_queries(mut self, force_post_queries: bool) -> Self {
+        self.options.force_post_queries = force_post_queries;
+        self
+    }
+
+    pub fn pretty_variables(mut self, pretty_variables: bool) -> Self {
+        self.options.pretty_variables = pretty_variables;
+        self
+    }
+
+    pub fn param_names(mut self, param_names: QueryParamNames) -> Self {
+        self.options.param_names = param_names;
+        self
+    }
+
+    pub fn subscription_transport(mut self, transport: SubscriptionTransport) -> Self {
+        self.options.subscription_transport = transport;
+        self
+    }
+
+    #[cfg(feature = "websocket")]
+    pub fn websocket_url(mut self, websocket_url: Url) -> Self {
+        self.options.websocket_url = Some(websocket_url);
+        self
+    }
+
+    #[cfg(feature = "cancellation")]
+    pub fn shutdown(mut self, shutdown: tokio_util::sync::CancellationToken) -> Self {
+        self.options.shutdown = Some(shutdown);
+        self
+    }
+
+    pub fn build(self) -> ClientOptions {
+        self.options
+    }
+}
@@ -0,0 +1,69 @@
+use json_patch::Patch;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{client::decode_bytes, errors::truncate_body, Error, Result};
+
+/// An update of a live query, as sent by the server when asked for JSON
+/// patches
+#[derive(Debug, Clone)]
+pub enum LiveQueryUpdate {
+    /// The whole response, including `data` and `errors`. The first update
+    /// of every connection is a snapshot
+    Snapshot(Value),
+    /// Changes to the previous response, see RFC 6902
+    Patch(Patch),
+}
+
+impl LiveQueryUpdate {
+    /// Patches are sent as arrays of operations, full responses as objects
+    pub(crate) fn decode(status: StatusCode, data: &[u8]) -> Result<Self> {
+        let value: Value =
+            serde_json::from_slice(data).map_err(|source| Error::DeserializationError {
+                source,
+                status: status.as_u16(),
+                body: truncate_body(data),
+            })?;
+        if !value.is_array() {
+            return Ok(Self::Snapshot(value));
+        }
+        let patch =
+            serde_json::from_value(value).map_err(|source| Error::DeserializationError {
+                source,
+                status: status.as_u16(),
+                body: truncate_body(data),
+            })?;
+        Ok(Self::Patch(patch))
+    }
+}
+
+/// The last response of a live query, kept to apply the patches to
+#[derive(Default)]
+pub(crate) struct PatchedResponse {
+    response: Option<Value>,
+}
+
+impl PatchedResponse {
+    /// Applies the update in the frame, then decodes the resulting response
+    pub(crate) fn decode<T>(&mut self, subpath: &str, status: StatusCode, data: &[u8]) -> Result<T>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let response = match LiveQueryUpdate::decode(status, data)? {
+            LiveQueryUpdate::Snapshot(response) => self.response.insert(response),
+            LiveQueryUpdate::Patch(patch) => {
+                let Some(response) = &mut self.response else {
+                    return Err(
+                        anyhow::anyhow!("received a JSON patch before a full response").into(),
+                    );
+                };
+                json_patch::patch(response, &patch)
+                    .map_err(|e| anyhow::anyhow!("failed to apply JSON patch: {}", e))?;
+                response
+            }
+        };
+        let data = serde_json::to_vec(response)?;
+        decode_bytes(subpath, status, &data)
+    }
+}
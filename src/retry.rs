@@ -0,0 +1,107 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime},
+};
+
+use reqwest::header::RETRY_AFTER;
+
+/// Controls how subscriptions and live queries reconnect after their
+/// connection ends or fails
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Maximum number of consecutive reconnection attempts before giving up
+    pub max_attempts: u32,
+    /// Delay before the first reconnection attempt, doubled on every
+    /// subsequent attempt
+    pub backoff: Duration,
+    /// Upper bound for the delay between attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns the delay before the given attempt, starting at 1
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        exponential_backoff(self.backoff, self.max_backoff, attempt)
+    }
+}
+
+/// Controls how failed queries are retried. The default policy doesn't retry
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every subsequent one. A random
+    /// jitter of up to half the delay is subtracted from it. If the response
+    /// has a `Retry-After` header, the delay it asks for is used instead
+    pub base_delay: Duration,
+    /// Upper bound for the delay between attempts
+    pub max_delay: Duration,
+    /// HTTP status codes which cause a request to be retried. Add 429 to
+    /// retry rate limited requests
+    pub status_codes: Vec<u16>,
+    /// Retry requests which failed to connect or timed out
+    pub retry_connection_errors: bool,
+    /// Also retry mutations. Disabled by default, since they're not
+    /// idempotent
+    pub retry_mutations: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            status_codes: vec![502, 503, 504],
+            retry_connection_errors: true,
+            retry_mutations: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before retrying the given attempt, starting at 1
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let delay = exponential_backoff(self.base_delay, self.max_delay, attempt);
+        // RandomState is randomly seeded, which is good enough for jitter
+        let jitter = (RandomState::new().build_hasher().finish() % 1000) as f64 / 1000.0;
+        delay.mul_f64(1.0 - jitter / 2.0)
+    }
+
+    pub(crate) fn should_retry(&self, result: &reqwest::Result<reqwest::Response>) -> bool {
+        match result {
+            Ok(resp) => self.status_codes.contains(&resp.status().as_u16()),
+            Err(error) => {
+                self.retry_connection_errors && (error.is_connect() || error.is_timeout())
+            }
+        }
+    }
+}
+
+/// Returns the delay asked for by the `Retry-After` header of the response,
+/// given either in seconds or as an HTTP date
+pub(crate) fn retry_after(resp: &reqwest::Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    // A date in the past allows retrying right away
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+fn exponential_backoff(base: Duration, max: Duration, attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    base.saturating_mul(factor).min(max)
+}
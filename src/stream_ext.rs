@@ -0,0 +1,43 @@
+use std::future::Future;
+
+use futures_util::StreamExt;
+
+use crate::{Result, Stream};
+
+/// Helpers for the streams returned by [`crate::Client::subscribe`] and
+/// [`crate::Client::live_query`]
+pub trait ResultStreamExt<R>: Stream<Item = Result<R>> + Sized {
+    /// Collects up to `n` items. Stops early when the stream ends, and fails
+    /// with the first error it yields
+    fn collect_n(self, n: usize) -> impl Future<Output = Result<Vec<R>>>;
+}
+
+impl<S, R> ResultStreamExt<R> for S
+where
+    S: Stream<Item = Result<R>>,
+{
+    async fn collect_n(self, n: usize) -> Result<Vec<R>> {
+        let mut stream = std::pin::pin!(self);
+        let mut items = Vec::new();
+        while items.len() < n {
+            match stream.next().await {
+                Some(item) => items.push(item?),
+                None => break,
+            }
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{stream, FutureExt};
+
+    use super::*;
+
+    #[test]
+    fn collects_until_the_end_with_an_unbounded_count() {
+        let items = stream::iter([Ok(1), Ok(2)]).collect_n(usize::MAX);
+        assert_eq!(items.now_or_never().unwrap().unwrap(), vec![1, 2]);
+    }
+}
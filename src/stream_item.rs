@@ -0,0 +1,42 @@
+use crate::{Error, ResponseError, Result};
+
+/// An item of a subscription or live query stream. Unlike plain `Result`
+/// items, it tells errors in a single frame apart from errors ending the
+/// stream
+#[derive(Debug)]
+pub enum StreamItem<R> {
+    Data(R),
+    /// The server sent GraphQL errors instead of data. The stream continues
+    /// unless the next item is [`StreamItem::StreamEnded`]
+    GraphQLError(ResponseError),
+    /// A frame couldn't be decoded. The stream continues
+    InvalidFrame(Error),
+    /// The stream ended, with the error which ended it, if any. For
+    /// WebSockets and server-sent events, no error means the server completed
+    /// the stream, so there's no point in reconnecting. Newline delimited
+    /// JSON streams can't tell a completed stream from a connection closed
+    /// without an error, both end without one. This is always the last item,
+    /// unless the stream is aborted or the client is shut down
+    StreamEnded(Option<Error>),
+}
+
+impl<R> StreamItem<R> {
+    pub(crate) fn from_frame(result: Result<R>) -> Self {
+        match result {
+            Ok(data) => Self::Data(data),
+            Err(Error::ResponseError(error)) => Self::GraphQLError(error),
+            Err(error) => Self::InvalidFrame(error),
+        }
+    }
+
+    /// Converts the item into the one yielded by streams of `Result`s, which
+    /// don't report the end of the stream
+    pub(crate) fn into_result(self) -> Option<Result<R>> {
+        match self {
+            Self::Data(data) => Some(Ok(data)),
+            Self::GraphQLError(error) => Some(Err(error.into())),
+            Self::InvalidFrame(error) => Some(Err(error)),
+            Self::StreamEnded(error) => error.map(Err),
+        }
+    }
+}
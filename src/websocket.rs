@@ -0,0 +1,414 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_stream::stream;
+use futures_util::{
+    future::{self, AbortRegistration, Abortable, Either},
+    SinkExt, StreamExt,
+};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, SEC_WEBSOCKET_PROTOCOL},
+    StatusCode, Url,
+};
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+use tracing::{debug, error};
+
+use crate::{
+    client::{decode_bytes, skip_undecodable_frames, until_aborted, with_idle_timeout},
+    Client, Error, GraphQLError, ResponseError, Result, Stream, StreamItem,
+};
+
+/// See https://github.com/enisdenjo/graphql-ws/blob/master/PROTOCOL.md
+const PROTOCOL: &str = "graphql-transport-ws";
+
+/// Each connection carries a single subscription, so the id is always the same
+const SUBSCRIPTION_ID: &str = "1";
+
+type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    ConnectionAck,
+    Ping,
+    Pong,
+    Next {
+        id: String,
+        payload: Value,
+    },
+    Error {
+        id: String,
+        payload: Vec<GraphQLError>,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+impl ServerMessage {
+    /// Returns the id of the subscription the message belongs to, if any
+    fn id(&self) -> Option<&str> {
+        match self {
+            Self::Next { id, .. } | Self::Error { id, .. } | Self::Complete { id } => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// Starts a subscription over a WebSocket connection. The operation is
+/// identified by its name, the server resolves the persisted operation from it
+pub(crate) async fn subscribe<R, D>(
+    url: &Url,
+    headers: HeaderMap,
+    subpath: &str,
+    variables: Option<Value>,
+    idle_timeout: Option<Duration>,
+    abort: AbortRegistration,
+    mut decode: D,
+) -> Result<impl Stream<Item = StreamItem<R>>>
+where
+    D: FnMut(&str, StatusCode, &[u8]) -> Result<R>,
+{
+    let mut socket = connect(url, headers).await?;
+    send(
+        &mut socket,
+        json!({
+            "id": SUBSCRIPTION_ID,
+            "type": "subscribe",
+            "payload": subscribe_payload(subpath, variables),
+        }),
+    )
+    .await?;
+
+    let subpath = subpath.to_string();
+    let stream = stream! {
+        let mut abort = Abortable::new(future::pending::<()>(), abort);
+        loop {
+            // Returning drops the socket, which closes the connection
+            let next = with_idle_timeout(next_message(&mut socket), idle_timeout);
+            let Some(message) = until_aborted(next, &mut abort).await else {
+                return;
+            };
+            let message = match message.and_then(|message| message) {
+                Ok(message) => message,
+                Err(error) => {
+                    yield StreamItem::StreamEnded(Some(error));
+                    return;
+                }
+            };
+            match message {
+                Some(ServerMessage::Next { id, payload }) if id == SUBSCRIPTION_ID => {
+                    let data = serde_json::to_vec(&payload).map_err(Error::from);
+                    let data = data.and_then(|data| decode(&subpath, StatusCode::OK, &data));
+                    yield StreamItem::from_frame(data);
+                }
+                Some(ServerMessage::Error { id, payload }) if id == SUBSCRIPTION_ID => {
+                    yield StreamItem::GraphQLError(ResponseError {
+                        status_code: StatusCode::OK.as_u16(),
+                        code: None,
+                        errors: payload,
+                        request_id: None,
+                    });
+                    break;
+                }
+                Some(ServerMessage::Complete { id }) if id == SUBSCRIPTION_ID => break,
+                Some(ServerMessage::Ping) => {
+                    if let Err(error) = send(&mut socket, json!({ "type": "pong" })).await {
+                        yield StreamItem::StreamEnded(Some(error));
+                        return;
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    yield StreamItem::StreamEnded(Some(Error::ConnectionClosed));
+                    return;
+                }
+            }
+        }
+        yield StreamItem::StreamEnded(None);
+    };
+
+    Ok(stream)
+}
+
+/// Opens a connection and waits for the server to acknowledge it
+async fn connect(url: &Url, headers: HeaderMap) -> Result<Socket> {
+    let mut request = url.as_str().into_client_request().map_err(ws_error)?;
+    request.headers_mut().extend(headers);
+    request
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(PROTOCOL));
+
+    debug!("websocket connection: {:?}", request);
+
+    let (mut socket, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .map_err(ws_error)?;
+
+    send(&mut socket, json!({ "type": "connection_init" })).await?;
+    loop {
+        match next_message(&mut socket).await? {
+            Some(ServerMessage::ConnectionAck) => return Ok(socket),
+            Some(ServerMessage::Ping) => send(&mut socket, json!({ "type": "pong" })).await?,
+            Some(_) => {}
+            None => {
+                return Err(
+                    anyhow::anyhow!("websocket closed before acknowledging connection").into(),
+                )
+            }
+        }
+    }
+}
+
+fn subscribe_payload(subpath: &str, variables: Option<Value>) -> Value {
+    let mut payload = json!({ "operationName": subpath });
+    if let Some(variables) = variables {
+        payload["variables"] = variables;
+    }
+    payload
+}
+
+/// Runs many subscriptions over a single WebSocket connection, see
+/// [`Client::subscription_manager`]. Cloning the manager shares the
+/// connection
+#[derive(Clone)]
+pub struct SubscriptionManager {
+    client: Client,
+    commands: UnboundedSender<Command>,
+    next_id: Arc<AtomicU64>,
+    skip_undecodable_frames: bool,
+}
+
+/// Requests from the subscriptions to the task driving the connection
+enum Command {
+    Subscribe {
+        id: String,
+        payload: Value,
+        messages: UnboundedSender<ServerMessage>,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+/// Tells the server to stop sending events once a subscription's stream is
+/// dropped
+struct CompleteOnDrop {
+    id: String,
+    commands: UnboundedSender<Command>,
+}
+
+impl Drop for CompleteOnDrop {
+    fn drop(&mut self) {
+        let id = std::mem::take(&mut self.id);
+        // The connection might be gone already, then there's nothing to do
+        let _ = self.commands.send(Command::Complete { id });
+    }
+}
+
+/// Connects to the server and returns a manager for the connection, along
+/// with the future driving it
+pub(crate) async fn subscription_manager(
+    client: Client,
+    url: &Url,
+    headers: HeaderMap,
+    idle_timeout: Option<Duration>,
+    skip_undecodable_frames: bool,
+) -> Result<(SubscriptionManager, impl Future<Output = ()>)> {
+    let socket = connect(url, headers).await?;
+    let (commands, receiver) = mpsc::unbounded_channel();
+    let manager = SubscriptionManager {
+        client,
+        commands,
+        next_id: Arc::new(AtomicU64::new(1)),
+        skip_undecodable_frames,
+    };
+    Ok((manager, drive(socket, receiver, idle_timeout)))
+}
+
+impl SubscriptionManager {
+    /// Like [`Client::subscribe`], but sends the subscription over the
+    /// manager's connection
+    pub fn subscribe<P, I, R>(&self, subpath: P, input: I) -> Result<impl Stream<Item = Result<R>>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let stream = self.subscribe_items(subpath, input)?;
+        Ok(stream.filter_map(|item| future::ready(item.into_result())))
+    }
+
+    /// Like [`Client::subscribe_items`], but sends the subscription over the
+    /// manager's connection. If the connection is lost, every subscription
+    /// ends with [`Error::ConnectionClosed`]
+    pub fn subscribe_items<P, I, R>(
+        &self,
+        subpath: P,
+        input: I,
+    ) -> Result<impl Stream<Item = StreamItem<R>>>
+    where
+        P: AsRef<str>,
+        I: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let subpath = subpath.as_ref().to_string();
+        let path = subpath.clone();
+        self.client.check_operation(&subpath)?;
+        let variables = serde_json::to_value(&input)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let (messages, mut receiver) = mpsc::unbounded_channel();
+        self.commands
+            .send(Command::Subscribe {
+                id: id.clone(),
+                payload: subscribe_payload(&subpath, Some(variables)),
+                messages,
+            })
+            .map_err(|_| Error::ConnectionClosed)?;
+        let guard = CompleteOnDrop {
+            id,
+            commands: self.commands.clone(),
+        };
+        let stream = stream! {
+            let _guard = guard;
+            loop {
+                let Some(message) = receiver.recv().await else {
+                    yield StreamItem::StreamEnded(Some(Error::ConnectionClosed));
+                    return;
+                };
+                match message {
+                    ServerMessage::Next { payload, .. } => {
+                        let data = serde_json::to_vec(&payload).map_err(Error::from);
+                        let data = data.and_then(|data| decode_bytes(&subpath, StatusCode::OK, &data));
+                        yield StreamItem::from_frame(data);
+                    }
+                    ServerMessage::Error { payload, .. } => {
+                        yield StreamItem::GraphQLError(ResponseError {
+                            status_code: StatusCode::OK.as_u16(),
+                            code: None,
+                            errors: payload,
+                            request_id: None,
+                        });
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+            yield StreamItem::StreamEnded(None);
+        };
+        Ok(skip_undecodable_frames(
+            stream,
+            &path,
+            self.skip_undecodable_frames,
+        ))
+    }
+}
+
+/// Reads messages from the connection and routes them to their
+/// subscriptions, until the connection fails or the manager and all its
+/// subscriptions are dropped
+async fn drive(
+    mut socket: Socket,
+    mut commands: UnboundedReceiver<Command>,
+    idle_timeout: Option<Duration>,
+) {
+    let mut subscriptions: HashMap<String, UnboundedSender<ServerMessage>> = HashMap::new();
+    loop {
+        // Reading a message is cancel safe, so whichever comes first wins
+        let next = {
+            let command = Box::pin(commands.recv());
+            let message = Box::pin(with_idle_timeout(next_message(&mut socket), idle_timeout));
+            match future::select(command, message).await {
+                Either::Left((command, _)) => Either::Left(command),
+                Either::Right((message, _)) => Either::Right(message),
+            }
+        };
+        let sent = match next {
+            Either::Left(None) => {
+                let _ = socket.close(None).await;
+                return;
+            }
+            Either::Left(Some(Command::Subscribe {
+                id,
+                payload,
+                messages,
+            })) => {
+                let message = json!({ "id": id, "type": "subscribe", "payload": payload });
+                subscriptions.insert(id, messages);
+                send(&mut socket, message).await
+            }
+            Either::Left(Some(Command::Complete { id })) => {
+                if subscriptions.remove(&id).is_none() {
+                    continue;
+                }
+                send(&mut socket, json!({ "id": id, "type": "complete" })).await
+            }
+            Either::Right(Ok(Ok(Some(ServerMessage::Ping)))) => {
+                send(&mut socket, json!({ "type": "pong" })).await
+            }
+            Either::Right(Ok(Ok(Some(message)))) => {
+                let Some(id) = message.id().map(str::to_string) else {
+                    continue;
+                };
+                // Errors and completions end the subscription
+                if matches!(message, ServerMessage::Next { .. }) {
+                    if let Some(subscription) = subscriptions.get(&id) {
+                        let _ = subscription.send(message);
+                    }
+                } else if let Some(subscription) = subscriptions.remove(&id) {
+                    let _ = subscription.send(message);
+                }
+                continue;
+            }
+            Either::Right(Ok(Ok(None))) => {
+                debug!("websocket connection closed by the server");
+                return;
+            }
+            Either::Right(Ok(Err(error)) | Err(error)) => Err(error),
+        };
+        // Dropping the senders ends the subscriptions
+        if let Err(error) = sent {
+            error!("websocket connection failed: {}", error);
+            return;
+        }
+    }
+}
+
+async fn send(socket: &mut Socket, message: Value) -> Result<()> {
+    socket
+        .send(Message::Text(message.to_string()))
+        .await
+        .map_err(ws_error)
+}
+
+/// Returns the next protocol message, or `None` once the socket is closed
+async fn next_message(socket: &mut Socket) -> Result<Option<ServerMessage>> {
+    while let Some(message) = socket.next().await {
+        let data = match message.map_err(ws_error)? {
+            Message::Text(text) => text.into_bytes(),
+            Message::Binary(data) => data,
+            Message::Close(_) => return Ok(None),
+            _ => continue,
+        };
+        return Ok(Some(serde_json::from_slice(&data)?));
+    }
+    Ok(None)
+}
+
+fn ws_error(error: tungstenite::Error) -> Error {
+    anyhow::anyhow!("websocket error: {}", error).into()
+}